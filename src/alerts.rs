@@ -0,0 +1,496 @@
+use common::now_ms;
+use common::serde_derive::{Deserialize, Serialize};
+use common::serde_json as json;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
+
+const DEFAULT_FAILURES_THRESHOLD: u32 = 3;
+const DEFAULT_UNREACHABLE_CYCLES: u32 = 3;
+/// The limit of connecting to the webhook and of each read and write.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_failures_threshold() -> u32 { DEFAULT_FAILURES_THRESHOLD }
+
+fn default_unreachable_cycles() -> u32 { DEFAULT_UNREACHABLE_CYCLES }
+
+/// Where the alerts are delivered, every alert is a JSON object like `{"ticker": "KMD", "event": ..., "message": ...}`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertBackend {
+    /// Runs the command with the alert written to its stdin and the message set to the `ALERT_MESSAGE` env var.
+    Command {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// POSTs the alert to the URL, only plain `http://` URLs are supported.
+    Webhook { url: String },
+    /// Appends the alert to the file, one JSON object per line.
+    File { path: String },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AlertsConf {
+    #[serde(default)]
+    pub backends: Vec<AlertBackend>,
+    /// Alert once the merge of a coin fails this number of times in a row.
+    #[serde(default = "default_failures_threshold")]
+    pub failures_threshold: u32,
+    /// Alert once the block number of a coin can't be obtained for this number of cycles in a row,
+    /// i.e. its Electrum servers or daemon are unreachable.
+    #[serde(default = "default_unreachable_cycles")]
+    pub unreachable_cycles: u32,
+}
+
+impl Default for AlertsConf {
+    fn default() -> AlertsConf {
+        AlertsConf {
+            backends: Vec::new(),
+            failures_threshold: DEFAULT_FAILURES_THRESHOLD,
+            unreachable_cycles: DEFAULT_UNREACHABLE_CYCLES,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AlertEvent {
+    RepeatedFailures {
+        failures: u32,
+    },
+    RpcUnreachable {
+        cycles: u32,
+    },
+    /// The pubkey has fewer P2PK unspents below the `output_threshold` than the notarizations need.
+    LowNotarizationUtxos {
+        pubkey: String,
+        count: usize,
+        threshold: usize,
+    },
+    /// The value is the output amount of the merge transaction in satoshis.
+    LargeMergeSent {
+        tx_hash: String,
+        value: u64,
+    },
+}
+
+impl fmt::Display for AlertEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlertEvent::RepeatedFailures { failures } => write!(f, "The merge failed {} times in a row", failures),
+            AlertEvent::RpcUnreachable { cycles } => write!(f, "The RPC is unreachable for {} cycles", cycles),
+            AlertEvent::LowNotarizationUtxos {
+                pubkey,
+                count,
+                threshold,
+            } => write!(
+                f,
+                "{} has {} notarization sized unspents, fewer than {}",
+                pubkey, count, threshold
+            ),
+            AlertEvent::LargeMergeSent { tx_hash, value } => {
+                write!(f, "Sent the merge transaction {} of {} satoshis", tx_hash, value)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    /// UNIX timestamp in seconds.
+    pub timestamp: u64,
+    pub ticker: String,
+    #[serde(flatten)]
+    pub event: AlertEvent,
+    pub message: String,
+}
+
+impl Alert {
+    pub fn new(ticker: &str, event: AlertEvent) -> Alert {
+        Alert {
+            timestamp: now_ms() / 1000,
+            ticker: ticker.to_owned(),
+            message: format!("{}: {}", ticker, event),
+            event,
+        }
+    }
+}
+
+/// The streaks of a coin, an alert is raised once per streak.
+#[derive(Default)]
+struct CoinAlertState {
+    failures: u32,
+    unreachable_cycles: u32,
+    /// The pubkeys alerted about until their notarization unspents are replenished.
+    low_utxos_pubkeys: HashSet<String>,
+}
+
+/// Raises the alerts on the merger events and delivers them to the backends from a background thread,
+/// so a slow backend doesn't delay the merges.
+pub struct Alerter {
+    conf: AlertsConf,
+    coins: Mutex<HashMap<String, CoinAlertState>>,
+    alerts: Option<Mutex<Sender<Alert>>>,
+}
+
+impl Alerter {
+    pub fn new(conf: AlertsConf) -> Alerter {
+        if conf.backends.is_empty() {
+            return Alerter::with_sender(conf, None);
+        }
+        let (alerts, alerts_rx) = mpsc::channel::<Alert>();
+        let backends = conf.backends.clone();
+        thread::spawn(move || {
+            for alert in alerts_rx {
+                for backend in backends.iter() {
+                    if let Err(e) = deliver(backend, &alert) {
+                        error!(error = %e, ?backend, "Failed to deliver the alert");
+                    }
+                }
+            }
+        });
+        Alerter::with_sender(conf, Some(alerts))
+    }
+
+    fn with_sender(conf: AlertsConf, alerts: Option<Sender<Alert>>) -> Alerter {
+        Alerter {
+            conf,
+            coins: Mutex::new(HashMap::new()),
+            alerts: alerts.map(Mutex::new),
+        }
+    }
+
+    fn coins(&self) -> MutexGuard<HashMap<String, CoinAlertState>> {
+        self.coins.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Logs the alert and passes it to the backends.
+    pub fn notify(&self, ticker: &str, event: AlertEvent) {
+        let alert = Alert::new(ticker, event);
+        warn!(alert = %alert.message, "Alert");
+        if let Some(alerts) = &self.alerts {
+            if alerts
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .send(alert)
+                .is_err()
+            {
+                error!("The alert delivery thread is stopped");
+            }
+        }
+    }
+
+    /// Counts the failed merges of the coin, alerting once `failures_threshold` merges fail in a row.
+    pub fn merge_finished(&self, ticker: &str, succeeded: bool) {
+        let failures = {
+            let mut coins = self.coins();
+            let state = coins.entry(ticker.to_owned()).or_default();
+            state.failures = if succeeded { 0 } else { state.failures + 1 };
+            state.failures
+        };
+        if failures > 0 && failures == self.conf.failures_threshold {
+            self.notify(ticker, AlertEvent::RepeatedFailures { failures });
+        }
+    }
+
+    /// Counts the cycles the coin RPC is unreachable in, alerting once there are `unreachable_cycles` in a row.
+    pub fn rpc_reachable(&self, ticker: &str, reachable: bool) {
+        let cycles = {
+            let mut coins = self.coins();
+            let state = coins.entry(ticker.to_owned()).or_default();
+            state.unreachable_cycles = if reachable { 0 } else { state.unreachable_cycles + 1 };
+            state.unreachable_cycles
+        };
+        if cycles > 0 && cycles == self.conf.unreachable_cycles {
+            self.notify(ticker, AlertEvent::RpcUnreachable { cycles });
+        }
+    }
+
+    /// Alerts once the `count` of the notarization sized unspents of the `pubkey` drops below the `threshold`,
+    /// the pubkey is alerted about again only after the count recovers.
+    pub fn notarization_utxos(&self, ticker: &str, pubkey: &str, count: usize, threshold: usize) {
+        let newly_low = {
+            let mut coins = self.coins();
+            let low_utxos_pubkeys = &mut coins.entry(ticker.to_owned()).or_default().low_utxos_pubkeys;
+            if count >= threshold {
+                low_utxos_pubkeys.remove(pubkey);
+                false
+            } else {
+                low_utxos_pubkeys.insert(pubkey.to_owned())
+            }
+        };
+        if newly_low {
+            let event = AlertEvent::LowNotarizationUtxos {
+                pubkey: pubkey.to_owned(),
+                count,
+                threshold,
+            };
+            self.notify(ticker, event);
+        }
+    }
+
+    /// Forgets the streaks of the coin removed from the config.
+    pub fn remove_coin(&self, ticker: &str) { self.coins().remove(ticker); }
+}
+
+fn deliver(backend: &AlertBackend, alert: &Alert) -> Result<(), String> {
+    let body = json::to_string(alert).map_err(|e| e.to_string())?;
+    match backend {
+        AlertBackend::Command { command, args } => run_command(command, args, &body, &alert.message),
+        AlertBackend::Webhook { url } => post_json(url, &body),
+        AlertBackend::File { path } => {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Error {} on opening {}", e, path))?;
+            writeln!(file, "{}", body).map_err(|e| format!("Error {} on writing {}", e, path))
+        },
+    }
+}
+
+fn run_command(command: &str, args: &[String], body: &str, message: &str) -> Result<(), String> {
+    let mut child = Command::new(command)
+        .args(args)
+        .env("ALERT_MESSAGE", message)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Error {} on running {}", e, command))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(body.as_bytes())
+            .map_err(|e| format!("Error {} on writing the alert to {}", e, command))?;
+    }
+    let status = child
+        .wait()
+        .map_err(|e| format!("Error {} on waiting for {}", e, command))?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", command, status));
+    }
+    Ok(())
+}
+
+/// Splits the `http://` URL into the `host:port` to connect to, the `Host` header and the path.
+fn parse_http_url(url: &str) -> Result<(String, &str, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Unsupported webhook URL {}, only http:// is supported", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("No host in the webhook URL {}", url));
+    }
+    // the port may be omitted, the brackets of an IPv6 address contain colons too
+    let addr = if host.ends_with(']') || !host.contains(':') {
+        format!("{}:80", host)
+    } else {
+        host.to_owned()
+    };
+    Ok((addr, host, path))
+}
+
+fn post_json(url: &str, body: &str) -> Result<(), String> {
+    let (addr, host, path) = parse_http_url(url)?;
+    let socket_addr = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("Error {} on resolving {}", e, addr))?
+        .next()
+        .ok_or_else(|| format!("{} is not resolved", addr))?;
+    let mut stream = TcpStream::connect_timeout(&socket_addr, WEBHOOK_TIMEOUT)
+        .map_err(|e| format!("Error {} on connecting to {}", e, addr))?;
+    stream
+        .set_read_timeout(Some(WEBHOOK_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(WEBHOOK_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    write!(
+        stream,
+        concat!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n",
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{}"
+        ),
+        path,
+        host,
+        body.len(),
+        body
+    )
+    .map_err(|e| format!("Error {} on posting to {}", e, url))?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| format!("Error {} on reading the response of {}", e, url))?;
+    let status: Option<u16> = status_line.split_whitespace().nth(1).and_then(|s| s.parse().ok());
+    match status {
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        _ => Err(format!("{} responded with {}", url, status_line.trim())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tiny_http::{Response, Server, StatusCode};
+
+    fn test_alerter(conf: AlertsConf) -> (Alerter, mpsc::Receiver<Alert>) {
+        let (alerts, alerts_rx) = mpsc::channel();
+        (Alerter::with_sender(conf, Some(alerts)), alerts_rx)
+    }
+
+    #[test]
+    fn test_streaks_alert_once() {
+        let (alerter, alerts) = test_alerter(AlertsConf::default());
+        for _ in 0..5 {
+            alerter.merge_finished("KMD", false);
+        }
+        alerter.merge_finished("RICK", false);
+        let alert = alerts.try_recv().unwrap();
+        assert_eq!(alert.ticker, "KMD");
+        assert_eq!(alert.event, AlertEvent::RepeatedFailures { failures: 3 });
+        assert!(alerts.try_recv().is_err());
+
+        alerter.merge_finished("KMD", true);
+        for _ in 0..3 {
+            alerter.merge_finished("KMD", false);
+        }
+        assert_eq!(alerts.try_recv().unwrap().event, AlertEvent::RepeatedFailures {
+            failures: 3
+        });
+
+        alerter.notarization_utxos("KMD", "02aa", 10, 20);
+        alerter.notarization_utxos("KMD", "02aa", 5, 20);
+        assert!(matches!(
+            alerts.try_recv().unwrap().event,
+            AlertEvent::LowNotarizationUtxos { count: 10, .. }
+        ));
+        assert!(alerts.try_recv().is_err());
+        alerter.notarization_utxos("KMD", "02aa", 20, 20);
+        alerter.notarization_utxos("KMD", "02aa", 19, 20);
+        assert!(alerts.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_timed_out_merge_counted_once() {
+        let conf = AlertsConf {
+            failures_threshold: 2,
+            ..AlertsConf::default()
+        };
+        let (alerter, alerts) = test_alerter(conf);
+        alerter.merge_finished("KMD", false);
+        // the next merge times out, the waiting side doesn't report it, and it finishes successfully later
+        alerter.merge_finished("KMD", true);
+        alerter.merge_finished("KMD", false);
+        assert!(alerts.try_recv().is_err());
+
+        // a timed out merge failing late is a single failure too
+        alerter.merge_finished("KMD", false);
+        assert_eq!(alerts.try_recv().unwrap().event, AlertEvent::RepeatedFailures {
+            failures: 2
+        });
+        assert!(alerts.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_parse_conf() {
+        let conf: AlertsConf = json::from_str(
+            r#"{
+                "backends": [
+                    {"type": "command", "command": "/usr/local/bin/notify"},
+                    {"type": "webhook", "url": "http://127.0.0.1:8080/alerts"},
+                    {"type": "file", "path": "./alerts.log"}
+                ],
+                "failures_threshold": 5
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(conf.backends.len(), 3);
+        assert_eq!(conf.failures_threshold, 5);
+        assert_eq!(conf.unreachable_cycles, DEFAULT_UNREACHABLE_CYCLES);
+    }
+
+    #[test]
+    fn test_parse_http_url() {
+        assert_eq!(
+            parse_http_url("http://localhost/alerts").unwrap(),
+            ("localhost:80".to_owned(), "localhost", "/alerts")
+        );
+        assert_eq!(
+            parse_http_url("http://[::1]:8080").unwrap(),
+            ("[::1]:8080".to_owned(), "[::1]:8080", "/")
+        );
+        assert!(parse_http_url("https://localhost/alerts").is_err());
+        assert!(parse_http_url("http:///alerts").is_err());
+    }
+
+    #[test]
+    fn test_webhook_posts_alert() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", server.server_addr());
+        let handle = thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let url = request.url().to_owned();
+            request.respond(Response::empty(StatusCode(204))).unwrap();
+            (url, body)
+        });
+
+        let alert = Alert::new("KMD", AlertEvent::RpcUnreachable { cycles: 3 });
+        deliver(&AlertBackend::Webhook { url }, &alert).unwrap();
+        let (path, body) = handle.join().unwrap();
+        assert_eq!(path, "/alerts");
+        let body: json::Value = json::from_str(&body).unwrap();
+        assert_eq!(body["ticker"], "KMD");
+        assert_eq!(body["event"], "rpc_unreachable");
+        assert_eq!(body["cycles"], 3);
+    }
+
+    #[test]
+    fn test_webhook_error_status() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", server.server_addr());
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            request.respond(Response::empty(StatusCode(500))).unwrap();
+        });
+        let alert = Alert::new("KMD", AlertEvent::RepeatedFailures { failures: 3 });
+        assert!(deliver(&AlertBackend::Webhook { url }, &alert).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_file_appends_alerts() {
+        let path = std::env::temp_dir().join(format!("notary_alerts_{}.log", std::process::id()));
+        let backend = AlertBackend::File {
+            path: path.to_string_lossy().into_owned(),
+        };
+        let tx_hash = "5e3a".to_owned();
+        deliver(
+            &backend,
+            &Alert::new("KMD", AlertEvent::LargeMergeSent { tx_hash, value: 100 }),
+        )
+        .unwrap();
+        deliver(
+            &backend,
+            &Alert::new("RICK", AlertEvent::RepeatedFailures { failures: 3 }),
+        )
+        .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<json::Value> = content.lines().map(|line| json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "large_merge_sent");
+        assert_eq!(lines[1]["ticker"], "RICK");
+    }
+}
@@ -0,0 +1,312 @@
+use crate::logging::{init_logging, LogFormat};
+use crate::MainError;
+use clap::{App, Arg, ArgMatches, ErrorKind as ClapErrorKind, SubCommand};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+
+const DEFAULT_INTERVAL_SECS: u64 = 15 * 60;
+
+/// The steps of the offline signing workflow, the regular cycles are run if none is given.
+#[derive(Debug)]
+pub enum OfflineStep {
+    /// Writes the unsigned transactions to the `output` file, doesn't need the keys.
+    Plan { output: String },
+    /// Signs the transactions of the `plan` file and writes them to the `output` file, doesn't need the RPC.
+    Sign { plan: String, output: String },
+    /// Broadcasts the transactions of the `signed` file.
+    Broadcast { signed: String },
+}
+
+/// The command line arguments shared by the notary tools binaries.
+#[derive(Debug)]
+pub struct CliArgs {
+    pub conf_path: String,
+    pub once: bool,
+    pub dry_run: bool,
+    pub dry_run_output: Option<String>,
+    pub coins: Vec<String>,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub log_file: Option<String>,
+    pub interval: Duration,
+    /// Read the keystore passphrase from stdin instead of prompting for it.
+    pub passphrase_stdin: bool,
+    pub offline_step: Option<OfflineStep>,
+}
+
+impl CliArgs {
+    /// Parses the process arguments, `--help` and `--version` print the info and exit the process.
+    /// The `plan`, `sign` and `broadcast` subcommands are accepted if `offline_steps` is set.
+    pub fn parse(
+        name: &'static str,
+        about: &'static str,
+        default_conf_path: &'static str,
+        offline_steps: bool,
+    ) -> Result<CliArgs, MainError> {
+        CliArgs::parse_with(name, about, default_conf_path, offline_steps, Vec::new()).map(|(args, _)| args)
+    }
+
+    /// Like `parse`, also accepting the `extra_args` of the binary, their values are read from the returned matches.
+    pub fn parse_with(
+        name: &'static str,
+        about: &'static str,
+        default_conf_path: &'static str,
+        offline_steps: bool,
+        extra_args: Vec<Arg<'static, 'static>>,
+    ) -> Result<(CliArgs, ArgMatches<'static>), MainError> {
+        let mut app = cli_app(name, about, default_conf_path).args(&extra_args);
+        if offline_steps {
+            app = app.subcommands(offline_step_subcommands());
+        }
+        let matches = match app.get_matches_safe() {
+            Ok(m) => m,
+            Err(e) => match e.kind {
+                ClapErrorKind::HelpDisplayed | ClapErrorKind::VersionDisplayed => e.exit(),
+                _ => return Err(MainError::InvalidCliArg(e.message)),
+            },
+        };
+        let args = CliArgs::from_matches(&matches, default_conf_path)?;
+        Ok((args, matches))
+    }
+
+    fn from_matches(matches: &ArgMatches, default_conf_path: &str) -> Result<CliArgs, MainError> {
+        let conf_path = matches.value_of("config").unwrap_or(default_conf_path).to_owned();
+        let coins = matches
+            .values_of("coin")
+            .map(|tickers| tickers.map(String::from).collect())
+            .unwrap_or_default();
+
+        let log_level = matches.value_of("log-level").unwrap_or("info");
+        let log_level = log_level
+            .parse()
+            .map_err(|_| MainError::InvalidCliArg(format!("Unknown log level {}", log_level)))?;
+        let log_format = matches
+            .value_of("log-format")
+            .unwrap_or("text")
+            .parse()
+            .map_err(MainError::InvalidCliArg)?;
+
+        let interval = match matches.value_of("interval") {
+            Some(secs) => match secs.parse::<u64>() {
+                Ok(0) => return Err(MainError::InvalidCliArg("Interval must be greater than 0".into())),
+                Ok(secs) => Duration::from_secs(secs),
+                Err(e) => return Err(MainError::InvalidCliArg(format!("Invalid interval {}: {}", secs, e))),
+            },
+            None => Duration::from_secs(DEFAULT_INTERVAL_SECS),
+        };
+
+        let offline_step = match matches.subcommand() {
+            ("plan", Some(sub)) => Some(OfflineStep::Plan {
+                output: sub.value_of("output").unwrap_or_default().to_owned(),
+            }),
+            ("sign", Some(sub)) => Some(OfflineStep::Sign {
+                plan: sub.value_of("plan").unwrap_or_default().to_owned(),
+                output: sub.value_of("output").unwrap_or_default().to_owned(),
+            }),
+            ("broadcast", Some(sub)) => Some(OfflineStep::Broadcast {
+                signed: sub.value_of("signed").unwrap_or_default().to_owned(),
+            }),
+            _ => None,
+        };
+
+        Ok(CliArgs {
+            conf_path,
+            once: matches.is_present("once"),
+            dry_run: matches.is_present("dry-run"),
+            dry_run_output: matches.value_of("dry-run-output").map(String::from),
+            coins,
+            log_level,
+            log_format,
+            log_file: matches.value_of("log-file").map(String::from),
+            interval,
+            passphrase_stdin: matches.is_present("passphrase-stdin"),
+            offline_step,
+        })
+    }
+
+    pub fn init_logging(&self) -> Result<WorkerGuard, MainError> {
+        init_logging(self.log_level, self.log_format, self.log_file.as_deref())
+    }
+
+    /// The coins selected with `--coin`, empty if every coin is processed.
+    pub fn selected_coins(&self) -> Vec<&str> { self.coins.iter().map(String::as_str).collect() }
+}
+
+/// Leaves only the `selected` coins, all the coins if it's empty. Fails if a selected coin is missing.
+pub fn retain_coins<T>(coins: &mut Vec<T>, selected: &[&str], ticker: impl Fn(&T) -> &str) -> Result<(), MainError> {
+    if let Some(unknown) = selected
+        .iter()
+        .find(|selected| !coins.iter().any(|coin| ticker(coin) == **selected))
+    {
+        return Err(MainError::UnknownCoin((*unknown).to_owned()));
+    }
+    if !selected.is_empty() {
+        coins.retain(|coin| selected.contains(&ticker(coin)));
+    }
+    Ok(())
+}
+
+fn cli_app(name: &'static str, about: &'static str, default_conf_path: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(about)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("PATH")
+                .default_value(default_conf_path)
+                .help("Path to the config file"),
+        )
+        .arg(Arg::with_name("once").long("once").help("Run a single cycle and exit"))
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Build and sign transactions without broadcasting them"),
+        )
+        .arg(
+            Arg::with_name("dry-run-output")
+                .long("dry-run-output")
+                .value_name("PATH")
+                .requires("dry-run")
+                .help("Append dry run reports as JSON lines to the given file instead of printing them"),
+        )
+        .arg(
+            Arg::with_name("coin")
+                .long("coin")
+                .value_name("TICKER")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Process only the given coin, can be repeated. All coins are processed by default"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .default_value("info")
+                .help("One of off, error, warn, info, debug, trace"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .default_value("text")
+                .help("text or json"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Append the log to the given file instead of stdout"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .help("Delay between cycles, 900 seconds by default, overridden by interval_secs of the coin"),
+        )
+        .arg(
+            Arg::with_name("passphrase-stdin")
+                .long("passphrase-stdin")
+                .help("Read the keystore passphrase from the first line of stdin instead of prompting for it"),
+        )
+}
+
+fn offline_step_subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        SubCommand::with_name("plan")
+            .about("Writes the unsigned transactions to be signed on an offline host, the keys are not needed")
+            .arg(
+                Arg::with_name("output")
+                    .value_name("PLAN_PATH")
+                    .required(true)
+                    .help("The file to write the unsigned transactions to"),
+            ),
+        SubCommand::with_name("sign")
+            .about("Signs the planned transactions, the coin RPC is not needed")
+            .arg(
+                Arg::with_name("plan")
+                    .value_name("PLAN_PATH")
+                    .required(true)
+                    .help("The file written by plan"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .value_name("SIGNED_PATH")
+                    .required(true)
+                    .help("The file to write the signed transactions to"),
+            ),
+        SubCommand::with_name("broadcast")
+            .about("Broadcasts the signed transactions")
+            .arg(
+                Arg::with_name("signed")
+                    .value_name("SIGNED_PATH")
+                    .required(true)
+                    .help("The file written by sign"),
+            ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT_CONF_PATH: &str = "./test.json";
+
+    fn parse(args: &[&str]) -> Result<CliArgs, MainError> {
+        let matches = cli_app("test", "Test", DEFAULT_CONF_PATH)
+            .subcommands(offline_step_subcommands())
+            .get_matches_from_safe(std::iter::once("test").chain(args.iter().copied()))
+            .map_err(|e| MainError::InvalidCliArg(e.message))?;
+        CliArgs::from_matches(&matches, DEFAULT_CONF_PATH)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.conf_path, DEFAULT_CONF_PATH);
+        assert!(!args.once);
+        assert!(!args.dry_run);
+        assert!(args.coins.is_empty());
+        assert_eq!(args.log_level, LevelFilter::INFO);
+        assert_eq!(args.log_format, LogFormat::Text);
+        assert_eq!(args.interval, Duration::from_secs(DEFAULT_INTERVAL_SECS));
+        assert!(args.offline_step.is_none());
+
+        let args = parse(&["--once", "-c", "./other.json"]).unwrap();
+        assert!(args.once);
+        assert_eq!(args.conf_path, "./other.json");
+    }
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(parse(&["--interval", "60"]).unwrap().interval, Duration::from_secs(60));
+        assert!(matches!(parse(&["--interval", "0"]), Err(MainError::InvalidCliArg(_))));
+        assert!(matches!(parse(&["--interval", "1m"]), Err(MainError::InvalidCliArg(_))));
+    }
+
+    #[test]
+    fn test_unknown_coin_is_rejected() {
+        let args = parse(&["--coin", "KMD", "--coin", "BTC"]).unwrap();
+        assert_eq!(args.selected_coins(), vec!["KMD", "BTC"]);
+
+        let mut coins = vec!["KMD", "LTC"];
+        let result = retain_coins(&mut coins, &args.selected_coins(), |coin| *coin);
+        assert!(matches!(result, Err(MainError::UnknownCoin(ticker)) if ticker == "BTC"));
+        assert_eq!(coins, vec!["KMD", "LTC"]);
+
+        let mut coins = vec!["KMD", "LTC", "BTC"];
+        retain_coins(&mut coins, &args.selected_coins(), |coin| *coin).unwrap();
+        assert_eq!(coins, vec!["KMD", "BTC"]);
+    }
+
+    #[test]
+    fn test_offline_step() {
+        let args = parse(&["sign", "plan.json", "signed.json"]).unwrap();
+        assert!(matches!(
+            args.offline_step,
+            Some(OfflineStep::Sign { plan, output }) if plan == "plan.json" && output == "signed.json"
+        ));
+    }
+}
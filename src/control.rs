@@ -0,0 +1,164 @@
+use common::serde_derive::Deserialize;
+use common::serde_json::{self as json, Value as Json};
+use std::io::Read;
+use std::net::ToSocketAddrs;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Response, Server};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, info};
+
+/// The merger loop replies to the commands right away, except `plan`.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Planning waits for the coin RPC.
+const PLAN_REPLY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The methods of the control API, e.g. `{"method": "pause", "params": {"ticker": "KMD"}}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// The state of every coin.
+    Status,
+    /// Starts the merge of the coin without waiting for its schedule.
+    MergeNow {
+        ticker: String,
+    },
+    /// Stops the scheduled merges of the coin until it's resumed.
+    Pause {
+        ticker: String,
+    },
+    Resume {
+        ticker: String,
+    },
+    /// The merge transactions that would be built for the coin, left unsigned.
+    Plan {
+        ticker: String,
+    },
+}
+
+impl ControlCommand {
+    fn reply_timeout(&self) -> Duration {
+        match self {
+            ControlCommand::Plan { .. } => PLAN_REPLY_TIMEOUT,
+            _ => REPLY_TIMEOUT,
+        }
+    }
+}
+
+/// Receives the result of the command, or the error replied with the status code 400.
+pub type ControlReply = mpsc::Sender<Result<Json, String>>;
+
+/// Replies the `result`, ignoring the requester that has timed out already.
+pub fn send_reply(reply: &ControlReply, result: Result<Json, String>) { reply.send(result).ok(); }
+
+/// The command along with the channel the result is replied to.
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: ControlReply,
+}
+
+/// Fails unless every address the `addr` resolves to is a loopback one, as the API is not authenticated.
+fn check_loopback(addr: &str) -> Result<(), String> {
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid control_addr {}: {}", addr, e))?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+        return Err(format!("control_addr {} must be a loopback address", addr));
+    }
+    Ok(())
+}
+
+fn json_response(status: u16, body: &Json, content_type: &Header) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_data(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type.clone())
+}
+
+/// Handles a single request, the status code is 400 if the command fails.
+fn handle_request(body: &str, requests: &UnboundedSender<ControlRequest>) -> (u16, Json) {
+    let command: ControlCommand = match json::from_str(body) {
+        Ok(c) => c,
+        Err(e) => return (400, json::json!({ "error": format!("Invalid request: {}", e) })),
+    };
+    let (reply, reply_rx) = mpsc::channel();
+    let reply_timeout = command.reply_timeout();
+    if requests.send(ControlRequest { command, reply }).is_err() {
+        return (500, json::json!({"error": "The merger is stopped"}));
+    }
+    match reply_rx.recv_timeout(reply_timeout) {
+        Ok(Ok(result)) => (200, json::json!({ "result": result })),
+        Ok(Err(e)) => (400, json::json!({ "error": e })),
+        Err(_) => (500, json::json!({"error": "No reply from the merger"})),
+    }
+}
+
+/// Serves the JSON control API at the loopback `addr` from a background thread.
+/// The commands are passed to the merger loop through `requests`, one `POST /` request per command.
+/// Every request is handled on its own thread, so a slow `plan` doesn't hold the other requests.
+pub fn serve_control(addr: &str, requests: UnboundedSender<ControlRequest>) -> Result<(), String> {
+    check_loopback(addr)?;
+    let server = Server::http(addr).map_err(|e| format!("Error {} on binding the control server to {}", e, addr))?;
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .map_err(|_| "Invalid Content-Type header".to_owned())?;
+    info!(%addr, "Serving the control API");
+
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let requests = requests.clone();
+            let content_type = content_type.clone();
+            thread::spawn(move || {
+                let (status, body) = if request.method() != &Method::Post {
+                    (405, json::json!({"error": "Only POST is supported"}))
+                } else {
+                    let mut body = String::new();
+                    match request.as_reader().read_to_string(&mut body) {
+                        Ok(_) => handle_request(&body, &requests),
+                        Err(e) => (
+                            400,
+                            json::json!({ "error": format!("Error {} on reading the request", e) }),
+                        ),
+                    }
+                };
+                if let Err(e) = request.respond(json_response(status, &body, &content_type)) {
+                    error!(error = %e, "Failed to respond to the control request");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let status: ControlCommand = json::from_str(r#"{"method": "status"}"#).unwrap();
+        assert!(matches!(status, ControlCommand::Status));
+
+        let pause: ControlCommand = json::from_str(r#"{"method": "pause", "params": {"ticker": "KMD"}}"#).unwrap();
+        assert!(matches!(pause, ControlCommand::Pause { ticker } if ticker == "KMD"));
+
+        let merge_now: ControlCommand =
+            json::from_str(r#"{"method": "merge_now", "params": {"ticker": "RICK"}}"#).unwrap();
+        assert_eq!(merge_now.reply_timeout(), REPLY_TIMEOUT);
+        assert!(matches!(merge_now, ControlCommand::MergeNow { ticker } if ticker == "RICK"));
+
+        let plan: ControlCommand = json::from_str(r#"{"method": "plan", "params": {"ticker": "RICK"}}"#).unwrap();
+        assert_eq!(plan.reply_timeout(), PLAN_REPLY_TIMEOUT);
+
+        assert!(json::from_str::<ControlCommand>(r#"{"method": "stop"}"#).is_err());
+        assert!(json::from_str::<ControlCommand>(r#"{"method": "plan"}"#).is_err());
+    }
+
+    #[test]
+    fn test_check_loopback() {
+        assert!(check_loopback("127.0.0.1:9185").is_ok());
+        assert!(check_loopback("[::1]:9185").is_ok());
+        assert!(check_loopback("0.0.0.0:9185").is_err());
+        assert!(check_loopback("10.0.0.1:9185").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::notary_rpc::{NotaryUnspent, SpendKind};
+use common::serde_derive::Serialize;
+use common::serde_json as json;
+use keys::KeyPair;
+use rpc::v1::types::H256 as H256Json;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+#[derive(Debug, Serialize)]
+pub struct DryRunInput {
+    tx_hash: H256Json,
+    tx_pos: u32,
+    value: u64,
+    height: Option<u64>,
+    pubkey: String,
+    kind: SpendKind,
+}
+
+impl DryRunInput {
+    pub fn new(unspent: &NotaryUnspent, keypair: &KeyPair) -> DryRunInput {
+        DryRunInput {
+            tx_hash: unspent.tx_hash.clone(),
+            tx_pos: unspent.tx_pos,
+            value: unspent.value,
+            height: unspent.height,
+            pubkey: keypair.public().to_string(),
+            kind: unspent.kind,
+        }
+    }
+}
+
+/// Prints the report of a transaction that was not broadcast due to `--dry-run`
+/// or appends it as a single JSON line to the `output` file.
+pub fn write_dry_run_report<T: serde::Serialize>(report: &T, output: Option<&str>) -> std::io::Result<()> {
+    match output {
+        Some(path) => {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", json::to_string(report)?)
+        },
+        None => {
+            println!("{}", json::to_string_pretty(report)?);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::privkey::key_pair_from_seed;
+
+    #[test]
+    fn test_report_appended_as_json_lines() {
+        let keypair = key_pair_from_seed("test1 komodo dpow notary nodes").unwrap();
+        let unspent = NotaryUnspent {
+            tx_hash: [1; 32].into(),
+            tx_pos: 2,
+            value: 100_000,
+            height: Some(10),
+            kind: SpendKind::P2pkh,
+        };
+        let input = DryRunInput::new(&unspent, &keypair);
+
+        let path = std::env::temp_dir().join(format!("notary_dry_run_{}.jsonl", std::process::id()));
+        let path_str = path.to_string_lossy().into_owned();
+        write_dry_run_report(&input, Some(&path_str)).unwrap();
+        write_dry_run_report(&input, Some(&path_str)).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<json::Value> = content.lines().map(|line| json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["tx_pos"], 2);
+        assert_eq!(lines[0]["value"], 100_000);
+        assert_eq!(lines[0]["kind"], "p2pkh");
+        assert_eq!(lines[0]["pubkey"], keypair.public().to_string());
+    }
+}
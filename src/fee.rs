@@ -0,0 +1,183 @@
+use crate::notary_rpc::NotaryRpcOps;
+use common::serde_derive::Deserialize;
+use common::serde_json::Value as Json;
+use tracing::warn;
+
+/// Used when neither `fee_policy` nor `txfee` of the `mm_conf` are set.
+const DEFAULT_SAT_PER_KB: u64 = 1000;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeePolicy {
+    /// The same fee for every merge transaction regardless of its size.
+    Fixed { amount: u64 },
+    /// The fee is calculated from the size of the signed transaction.
+    PerKb { sat_per_kb: u64 },
+    /// The fee rate is requested from the coin RPC client (`blockchain.estimatefee` for Electrum).
+    /// `fallback_sat_per_kb` is used when the estimation fails.
+    Estimate { n_blocks: u32, fallback_sat_per_kb: u64 },
+}
+
+impl FeePolicy {
+    /// The default policy charges `txfee` of the coin config per kB of the transaction.
+    pub fn from_mm_conf(mm_conf: &Json) -> FeePolicy {
+        let sat_per_kb = match mm_conf["txfee"].as_u64() {
+            Some(0) | None => DEFAULT_SAT_PER_KB,
+            Some(txfee) => txfee,
+        };
+        FeePolicy::PerKb { sat_per_kb }
+    }
+}
+
+/// The fee rule resolved for a single merge attempt.
+#[derive(Clone, Copy, Debug)]
+pub enum TxFee {
+    Fixed(u64),
+    PerKb(u64),
+}
+
+impl TxFee {
+    fn amount(self, tx_size: usize) -> u64 {
+        match self {
+            TxFee::Fixed(amount) => amount,
+            TxFee::PerKb(sat_per_kb) => (tx_size as u64 * sat_per_kb + 999) / 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FeeSettings {
+    policy: FeePolicy,
+    max_fee: Option<u64>,
+}
+
+impl FeeSettings {
+    pub fn new(policy: FeePolicy, max_fee: Option<u64>) -> FeeSettings { FeeSettings { policy, max_fee } }
+
+    /// Resolves the fee rule, the fee rate is requested from the `rpc` if `FeePolicy::Estimate` is used.
+    pub fn tx_fee(&self, rpc: &impl NotaryRpcOps, decimals: u8) -> TxFee {
+        match self.policy {
+            FeePolicy::Fixed { amount } => TxFee::Fixed(amount),
+            FeePolicy::PerKb { sat_per_kb } => TxFee::PerKb(sat_per_kb),
+            FeePolicy::Estimate {
+                n_blocks,
+                fallback_sat_per_kb,
+            } => match rpc.estimate_fee_rate(decimals, n_blocks) {
+                Ok(sat_per_kb) if sat_per_kb > 0 => TxFee::PerKb(sat_per_kb),
+                Ok(_) => {
+                    warn!(
+                        fallback_sat_per_kb,
+                        "Fee estimation is not available, using the fallback fee"
+                    );
+                    TxFee::PerKb(fallback_sat_per_kb)
+                },
+                Err(e) => {
+                    warn!(error = %e, fallback_sat_per_kb, "Fee estimation failed, using the fallback fee");
+                    TxFee::PerKb(fallback_sat_per_kb)
+                },
+            },
+        }
+    }
+
+    /// Calculates the fee of the transaction of the given size limited by `max_fee`.
+    pub fn fee_amount(&self, tx_fee: TxFee, tx_size: usize) -> u64 {
+        let amount = tx_fee.amount(tx_size);
+        match self.max_fee {
+            Some(max_fee) if amount > max_fee => {
+                warn!(
+                    fee = amount,
+                    max_fee, "Fee exceeds the configured cap, using the cap instead"
+                );
+                max_fee
+            },
+            _ => amount,
+        }
+    }
+
+    /// The highest fee a transaction of the given size may pay.
+    /// `None` if it depends on the fee estimation and `max_fee` is not set.
+    pub fn fee_limit(&self, tx_size: usize) -> Option<u64> {
+        let policy_fee = match self.policy {
+            FeePolicy::Fixed { amount } => Some(amount),
+            FeePolicy::PerKb { sat_per_kb } => Some(TxFee::PerKb(sat_per_kb).amount(tx_size)),
+            FeePolicy::Estimate { .. } => None,
+        };
+        match (policy_fee, self.max_fee) {
+            (Some(amount), Some(max_fee)) => Some(amount.min(max_fee)),
+            (amount, max_fee) => amount.or(max_fee),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notary_rpc::tests::TestRpc;
+    use common::serde_json::json;
+
+    #[test]
+    fn test_per_kb_rounds_up() {
+        assert_eq!(TxFee::PerKb(1000).amount(250), 250);
+        assert_eq!(TxFee::PerKb(1500).amount(1), 2);
+        assert_eq!(TxFee::PerKb(1000).amount(1001), 1001);
+        assert_eq!(TxFee::PerKb(10).amount(1001), 11);
+        assert_eq!(TxFee::Fixed(5000).amount(100_000), 5000);
+    }
+
+    #[test]
+    fn test_zero_size_and_rate() {
+        assert_eq!(TxFee::PerKb(1000).amount(0), 0);
+        assert_eq!(TxFee::PerKb(0).amount(1000), 0);
+        let fee = FeeSettings::new(FeePolicy::PerKb { sat_per_kb: 1000 }, Some(100));
+        assert_eq!(fee.fee_amount(TxFee::PerKb(1000), 0), 0);
+    }
+
+    #[test]
+    fn test_max_fee_caps_the_fee() {
+        let fee = FeeSettings::new(FeePolicy::PerKb { sat_per_kb: 1000 }, Some(10_000));
+        assert_eq!(fee.fee_amount(TxFee::PerKb(1000), 5000), 5000);
+        assert_eq!(fee.fee_amount(TxFee::PerKb(1000), 10_000), 10_000);
+        assert_eq!(fee.fee_amount(TxFee::PerKb(1000), 50_000), 10_000);
+        assert_eq!(fee.fee_amount(TxFee::Fixed(20_000), 100), 10_000);
+
+        let uncapped = FeeSettings::new(FeePolicy::PerKb { sat_per_kb: 1000 }, None);
+        assert_eq!(uncapped.fee_amount(TxFee::PerKb(1000), 50_000), 50_000);
+    }
+
+    #[test]
+    fn test_fee_limit() {
+        let per_kb = FeeSettings::new(FeePolicy::PerKb { sat_per_kb: 1000 }, Some(10_000));
+        assert_eq!(per_kb.fee_limit(5000), Some(5000));
+        assert_eq!(per_kb.fee_limit(50_000), Some(10_000));
+
+        let fixed = FeeSettings::new(FeePolicy::Fixed { amount: 1000 }, None);
+        assert_eq!(fixed.fee_limit(50_000), Some(1000));
+
+        let estimate = FeePolicy::Estimate {
+            n_blocks: 2,
+            fallback_sat_per_kb: 1000,
+        };
+        assert_eq!(FeeSettings::new(estimate.clone(), None).fee_limit(1000), None);
+        assert_eq!(FeeSettings::new(estimate, Some(3000)).fee_limit(1000), Some(3000));
+    }
+
+    #[test]
+    fn test_estimate_falls_back_on_failure() {
+        let estimate = FeePolicy::Estimate {
+            n_blocks: 2,
+            fallback_sat_per_kb: 3000,
+        };
+        let tx_fee = FeeSettings::new(estimate, None).tx_fee(&TestRpc::default(), 8);
+        assert!(matches!(tx_fee, TxFee::PerKb(3000)));
+    }
+
+    #[test]
+    fn test_policy_from_mm_conf() {
+        let policy = FeePolicy::from_mm_conf(&json!({"txfee": 10000}));
+        assert!(matches!(policy, FeePolicy::PerKb { sat_per_kb: 10000 }));
+        let policy = FeePolicy::from_mm_conf(&json!({"txfee": 0}));
+        assert!(matches!(policy, FeePolicy::PerKb { sat_per_kb } if sat_per_kb == DEFAULT_SAT_PER_KB));
+        let policy = FeePolicy::from_mm_conf(&json!({}));
+        assert!(matches!(policy, FeePolicy::PerKb { sat_per_kb } if sat_per_kb == DEFAULT_SAT_PER_KB));
+    }
+}
@@ -0,0 +1,156 @@
+use common::serde_json::{self as json, Value as Json};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const SUBSCRIBE_METHOD: &str = "blockchain.headers.subscribe";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The connection is pinged after this period of silence, so the server doesn't drop it as idle.
+const PING_AFTER: Duration = Duration::from_secs(60);
+/// The delay before reconnecting once every server has failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderEvent {
+    /// The tip has advanced to the given height.
+    Tip(u64),
+    /// The subscription is lost until the next `Tip`.
+    Lost,
+}
+
+/// The plain TCP `host:port` addresses of the Electrum servers of the `electrum` activation command.
+/// SSL servers are skipped, the subscription connection doesn't support TLS.
+pub fn tcp_electrum_servers(activation_command: &Json) -> Vec<String> {
+    let servers = match activation_command["servers"].as_array() {
+        Some(s) if activation_command["method"] == "electrum" => s,
+        _ => return Vec::new(),
+    };
+    servers
+        .iter()
+        .filter(|server| server["protocol"].as_str().unwrap_or("TCP") == "TCP")
+        .filter_map(|server| server["url"].as_str().map(String::from))
+        .collect()
+}
+
+/// Subscribes to the block headers of the Electrum `servers` on a dedicated connection in a background thread.
+/// `on_event` is called whenever the tip advances or the subscription is lost,
+/// the thread exits once it returns false. The servers are tried in turn until one of them is connected.
+pub fn spawn_header_subscription(
+    ticker: String,
+    servers: Vec<String>,
+    on_event: impl Fn(HeaderEvent) -> bool + Send + 'static,
+) -> io::Result<()> {
+    let name = format!("{}-headers", ticker);
+    std::thread::Builder::new().name(name).spawn(move || {
+        let mut last_tip = 0;
+        loop {
+            for server in servers.iter() {
+                match subscribe(server, &mut last_tip, &on_event) {
+                    Ok(false) => return,
+                    Ok(true) => warn!(%ticker, %server, "The header subscription is closed by the server"),
+                    Err(e) => warn!(%ticker, %server, error = %e, "The header subscription failed"),
+                }
+                if !on_event(HeaderEvent::Lost) {
+                    return;
+                }
+            }
+            std::thread::sleep(RECONNECT_DELAY);
+        }
+    })?;
+    Ok(())
+}
+
+/// Reads the header notifications of the `server` until the connection is closed.
+/// Returns false if `on_event` asked to stop.
+fn subscribe(server: &str, last_tip: &mut u64, on_event: &impl Fn(HeaderEvent) -> bool) -> io::Result<bool> {
+    let addr = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The server address is not resolved"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(PING_AFTER))?;
+    send_request(&mut stream, 0, SUBSCRIBE_METHOD)?;
+    info!(%server, "Subscribed to the block headers");
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    let mut request_id = 0;
+    let mut connected = false;
+    loop {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(true),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                // the bytes read so far are kept in the `line`
+                request_id += 1;
+                send_request(&mut stream, request_id, "server.ping")?;
+                continue;
+            },
+            Err(e) => return Err(e),
+        }
+
+        if let Some(height) = header_height(&line) {
+            debug!(%server, height, "Got the block header");
+            // the tip is reported on every new connection, so the subscription is known to be restored
+            if height > *last_tip || !connected {
+                connected = true;
+                *last_tip = height;
+                if !on_event(HeaderEvent::Tip(height)) {
+                    return Ok(false);
+                }
+            }
+        }
+        line.clear();
+    }
+}
+
+fn send_request(stream: &mut TcpStream, id: u64, method: &str) -> io::Result<()> {
+    let request = json::json!({"jsonrpc": "2.0", "id": id, "method": method, "params": []});
+    writeln!(stream, "{}", request)
+}
+
+/// The height of the header of either the subscription response or the notification, `None` for other messages.
+fn header_height(line: &str) -> Option<u64> {
+    let message: Json = json::from_str(line).ok()?;
+    let header = if message["method"] == SUBSCRIBE_METHOD {
+        &message["params"][0]
+    } else {
+        &message["result"]
+    };
+    header["height"].as_u64().or_else(|| header["block_height"].as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_height() {
+        let response = r#"{"jsonrpc":"2.0","id":0,"result":{"height":2335000,"hex":"04000000"}}"#;
+        assert_eq!(header_height(response), Some(2335000));
+        let notification =
+            r#"{"jsonrpc":"2.0","method":"blockchain.headers.subscribe","params":[{"height":2335001,"hex":"04"}]}"#;
+        assert_eq!(header_height(notification), Some(2335001));
+        let ping = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        assert_eq!(header_height(ping), None);
+        assert_eq!(header_height("not json"), None);
+    }
+
+    #[test]
+    fn test_tcp_electrum_servers() {
+        let activation = json::json!({
+            "method": "electrum",
+            "servers": [
+                {"url": "electrum1.cipig.net:10001"},
+                {"url": "electrum2.cipig.net:20001", "protocol": "SSL"},
+                {"url": "electrum3.cipig.net:10001", "protocol": "TCP"},
+            ]
+        });
+        assert_eq!(tcp_electrum_servers(&activation), vec![
+            "electrum1.cipig.net:10001".to_owned(),
+            "electrum3.cipig.net:10001".to_owned(),
+        ]);
+        assert!(tcp_electrum_servers(&json::json!({"method": "enable"})).is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::notary_keys::{KeyEntry, NotaryKeyPair};
+use crate::{write_atomically, MainError};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use common::serde_derive::{Deserialize, Serialize};
+use common::serde_json as json;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use scrypt::{scrypt, ScryptParams};
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+use tracing::warn;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The environment variable checked for the keystore passphrase before prompting for it.
+pub const PASSPHRASE_ENV: &str = "NOTARY_KEYSTORE_PASSPHRASE";
+/// Version 1 keystores contain the passphrases only, version 2 contains the typed `KeyEntry` list.
+const KEYSTORE_VERSION: u32 = 2;
+const PASSPHRASES_KEYSTORE_VERSION: u32 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+/// scrypt N = 2^15, r = 8, p = 1 takes about 100ms and 32MB of memory.
+const DEFAULT_LOG_N: u8 = 15;
+const DEFAULT_R: u32 = 8;
+const DEFAULT_P: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ScryptConf {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    /// Hex encoded.
+    salt: String,
+}
+
+/// The keys serialized as a JSON array and encrypted with XChaCha20-Poly1305
+/// using the key derived from the passphrase with scrypt.
+#[derive(Debug, Deserialize, Serialize)]
+struct KeystoreFile {
+    version: u32,
+    kdf: ScryptConf,
+    /// Hex encoded.
+    nonce: String,
+    /// Hex encoded, includes the authentication tag.
+    ciphertext: String,
+}
+
+impl KeystoreFile {
+    /// The version and the KDF parameters are authenticated along with the ciphertext.
+    fn associated_data(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.version, self.kdf.log_n, self.kdf.r, self.kdf.p, self.kdf.salt
+        )
+    }
+}
+
+fn derive_key(passphrase: &str, kdf: &ScryptConf) -> Result<Zeroizing<[u8; KEY_LEN]>, String> {
+    let salt = hex::decode(&kdf.salt).map_err(|e| format!("Invalid keystore salt: {}", e))?;
+    let params =
+        ScryptParams::new(kdf.log_n, kdf.r, kdf.p).map_err(|e| format!("Invalid keystore scrypt params: {}", e))?;
+    let mut key = Zeroizing::new([0; KEY_LEN]);
+    scrypt(passphrase.as_bytes(), &salt, &params, key.as_mut())
+        .map_err(|e| format!("Error {} on deriving the key", e))?;
+    Ok(key)
+}
+
+fn encrypt_keys(keys: &[KeyEntry], passphrase: &str) -> Result<KeystoreFile, String> {
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut file = KeystoreFile {
+        version: KEYSTORE_VERSION,
+        kdf: ScryptConf {
+            log_n: DEFAULT_LOG_N,
+            r: DEFAULT_R,
+            p: DEFAULT_P,
+            salt: hex::encode(&salt),
+        },
+        nonce: hex::encode(&nonce),
+        ciphertext: String::new(),
+    };
+    let key = derive_key(passphrase, &file.kdf)?;
+    let plaintext = Zeroizing::new(json::to_vec(keys).map_err(|e| e.to_string())?);
+    let aad = file.associated_data();
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .encrypt(XNonce::from_slice(&nonce), Payload {
+            msg: &plaintext,
+            aad: aad.as_bytes(),
+        })
+        .map_err(|_| "Failed to encrypt the keys".to_owned())?;
+    file.ciphertext = hex::encode(&ciphertext);
+    Ok(file)
+}
+
+fn decrypt_keys(file: &KeystoreFile, passphrase: &str) -> Result<Zeroizing<Vec<KeyEntry>>, String> {
+    if file.version != KEYSTORE_VERSION && file.version != PASSPHRASES_KEYSTORE_VERSION {
+        return Err(format!("Unsupported keystore version {}", file.version));
+    }
+    let nonce = hex::decode(&file.nonce).map_err(|e| format!("Invalid keystore nonce: {}", e))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(format!("Invalid keystore nonce length {}", nonce.len()));
+    }
+    let ciphertext = hex::decode(&file.ciphertext).map_err(|e| format!("Invalid keystore ciphertext: {}", e))?;
+    let key = derive_key(passphrase, &file.kdf)?;
+    let aad = file.associated_data();
+    let plaintext = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .decrypt(XNonce::from_slice(&nonce), Payload {
+            msg: &ciphertext,
+            aad: aad.as_bytes(),
+        })
+        .map_err(|_| "Wrong passphrase or corrupted keystore".to_owned())
+        .map(Zeroizing::new)?;
+    if file.version == PASSPHRASES_KEYSTORE_VERSION {
+        let mut passphrases: Zeroizing<Vec<String>> = json::from_slice(&plaintext)
+            .map(Zeroizing::new)
+            .map_err(|_| "Invalid keystore content".to_owned())?;
+        let keys = passphrases
+            .drain(..)
+            .map(|passphrase| KeyEntry::Passphrase { passphrase })
+            .collect();
+        return Ok(Zeroizing::new(keys));
+    }
+    json::from_slice(&plaintext)
+        .map(Zeroizing::new)
+        .map_err(|_| "Invalid keystore content".to_owned())
+}
+
+/// Decrypts the keys of the keystore at `path`.
+pub fn load_keys(path: &str, passphrase: &str) -> Result<Zeroizing<Vec<KeyEntry>>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Error {} on reading {}", e, path))?;
+    let file: KeystoreFile = json::from_str(&content).map_err(|e| format!("Error {} on parsing {}", e, path))?;
+    decrypt_keys(&file, passphrase)
+}
+
+/// Encrypts the `keys` with a fresh salt and nonce and replaces the keystore at `path`.
+pub fn save_keys(path: &str, passphrase: &str, keys: &[KeyEntry]) -> Result<(), String> {
+    let file = encrypt_keys(keys, passphrase)?;
+    let content = json::to_string_pretty(&file).map_err(|e| e.to_string())?;
+    write_atomically(path, content.as_bytes())
+}
+
+/// Creates an empty keystore, fails if the `path` already exists.
+pub fn create_keystore(path: &str, passphrase: &str) -> Result<(), String> {
+    if Path::new(path).exists() {
+        return Err(format!("{} already exists", path));
+    }
+    save_keys(path, passphrase, &[])
+}
+
+/// Reads a secret from the first line of stdin or prompts for it on the terminal without echo.
+pub fn read_secret(prompt: &str, from_stdin: bool) -> Result<Zeroizing<String>, String> {
+    if from_stdin {
+        let mut line = Zeroizing::new(String::new());
+        std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| format!("Error {} on reading stdin", e))?;
+        let len = line.trim_end_matches(|c| c == '\n' || c == '\r').len();
+        // truncating keeps the secret in the same zeroizing buffer
+        line.truncate(len);
+        return Ok(line);
+    }
+    rpassword::read_password_from_tty(Some(prompt))
+        .map(Zeroizing::new)
+        .map_err(|e| format!("Error {} on reading the terminal", e))
+}
+
+/// Takes the passphrase from the `NOTARY_KEYSTORE_PASSPHRASE` environment variable,
+/// then from stdin if `from_stdin` is set, and prompts for it otherwise.
+/// The variable is removed from the process environment once read.
+pub fn read_passphrase(from_stdin: bool) -> Result<Zeroizing<String>, String> {
+    read_passphrase_with(from_stdin, read_secret).map(|(passphrase, _)| passphrase)
+}
+
+/// `read_passphrase` taking stdin and the terminal input from `read_secret`.
+/// Also returns whether the passphrase was prompted for, the environment can't tell it once the variable is removed.
+pub fn read_passphrase_with<F>(from_stdin: bool, read_secret: F) -> Result<(Zeroizing<String>, bool), String>
+where
+    F: FnOnce(&str, bool) -> Result<Zeroizing<String>, String>,
+{
+    let env_passphrase = std::env::var(PASSPHRASE_ENV).ok();
+    match env_passphrase {
+        Some(passphrase) => {
+            std::env::remove_var(PASSPHRASE_ENV);
+            Ok((Zeroizing::new(passphrase), false))
+        },
+        None => Ok((read_secret("Keystore passphrase: ", from_stdin)?, !from_stdin)),
+    }
+}
+
+/// Where the notary keys are taken from, shared by the configs of the tools.
+#[derive(Deserialize)]
+pub struct KeysConf {
+    /// Plaintext passphrases, deprecated in favor of the `keystore_path`.
+    #[serde(default)]
+    seeds: Vec<String>,
+    /// Plaintext keys, deprecated in favor of the `keystore_path`.
+    #[serde(default)]
+    keys: Vec<KeyEntry>,
+    /// The keystore created with `notary_keystore`.
+    #[serde(default)]
+    keystore_path: Option<String>,
+}
+
+impl fmt::Debug for KeysConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeysConf")
+            .field("seeds", &format_args!("[{} redacted]", self.seeds.len()))
+            .field("keys", &self.keys)
+            .field("keystore_path", &self.keystore_path)
+            .finish()
+    }
+}
+
+impl Drop for KeysConf {
+    fn drop(&mut self) {
+        self.seeds.zeroize();
+        self.keys.zeroize();
+    }
+}
+
+impl KeysConf {
+    /// Derives the notary keys, decrypting the keystore with the passphrase obtained by `read_passphrase`.
+    /// WIF keys must match one of `wif_prefixes`, the `wiftype`s of the processed coins by the ticker.
+    /// The plaintext keys are wiped from memory right after the derivation.
+    pub fn key_pairs(
+        &mut self,
+        passphrase_stdin: bool,
+        wif_prefixes: &[(&str, u8)],
+    ) -> Result<Vec<NotaryKeyPair>, MainError> {
+        let has_plaintext = !self.seeds.is_empty() || !self.keys.is_empty();
+        let keys = match (&self.keystore_path, has_plaintext) {
+            (Some(_), true) => {
+                return Err(MainError::String(
+                    "keystore_path can't be set along with seeds or keys".into(),
+                ))
+            },
+            (Some(path), false) => {
+                let passphrase = read_passphrase(passphrase_stdin)?;
+                load_keys(path, &passphrase)?
+            },
+            (None, true) => {
+                warn!("Plaintext keys in the config are deprecated, move them to a keystore with notary_keystore");
+                let passphrases = self
+                    .seeds
+                    .drain(..)
+                    .map(|passphrase| KeyEntry::Passphrase { passphrase });
+                let keys = passphrases.chain(self.keys.drain(..)).collect();
+                Zeroizing::new(keys)
+            },
+            (None, false) => {
+                return Err(MainError::String(
+                    "Either seeds, keys or keystore_path must be set".into(),
+                ))
+            },
+        };
+        keys.iter().map(|key| key.key_pair(wif_prefixes)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: &str = "test1 komodo dpow notary nodes";
+    const PASSPHRASE: &str = "keystore test passphrase";
+
+    fn seed_entry() -> KeyEntry {
+        KeyEntry::Passphrase {
+            passphrase: SEED.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_keys() {
+        let file = encrypt_keys(&[seed_entry()], PASSPHRASE).unwrap();
+        let content = json::to_string(&file).unwrap();
+        assert!(!content.contains(SEED));
+
+        let keys = decrypt_keys(&file, PASSPHRASE).unwrap();
+        assert_eq!(keys.len(), 1);
+        match &keys[0] {
+            KeyEntry::Passphrase { passphrase } => assert_eq!(passphrase, SEED),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_wrong_passphrase() {
+        let file = encrypt_keys(&[seed_entry()], PASSPHRASE).unwrap();
+        let error = decrypt_keys(&file, "wrong passphrase").unwrap_err();
+        assert!(!error.contains(SEED));
+        assert!(!error.contains("wrong passphrase"));
+    }
+
+    #[test]
+    fn test_decrypt_tampered_kdf_params() {
+        let mut file = encrypt_keys(&[seed_entry()], PASSPHRASE).unwrap();
+        file.kdf.salt = hex::encode(&[0; SALT_LEN]);
+        decrypt_keys(&file, PASSPHRASE).unwrap_err();
+    }
+
+    #[test]
+    fn test_keys_conf_debug_and_errors_hide_seeds() {
+        let json = format!(
+            r#"{{"seeds":["{}"],"keys":[{{"type":"passphrase","passphrase":"{}"}}]}}"#,
+            SEED, SEED
+        );
+        let mut conf: KeysConf = json::from_str(&json).unwrap();
+        assert!(!format!("{:?}", conf).contains(SEED));
+
+        let keypairs = conf.key_pairs(false, &[]).unwrap();
+        assert_eq!(keypairs.len(), 2);
+        assert!(conf.seeds.is_empty());
+        assert!(conf.keys.is_empty());
+        assert!(!format!("{:?}", keypairs).contains(SEED));
+
+        let mut conf: KeysConf = json::from_str(&format!(
+            r#"{{"seeds":["{}"],"keystore_path":"./keystore.json"}}"#,
+            SEED
+        ))
+        .unwrap();
+        let error = conf.key_pairs(false, &[]).unwrap_err();
+        assert!(!format!("{:?}", error).contains(SEED));
+    }
+}
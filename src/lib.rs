@@ -0,0 +1,137 @@
+pub mod alerts;
+pub mod cli;
+pub mod control;
+pub mod dry_run;
+pub mod fee;
+pub mod headers;
+pub mod keystore;
+pub mod logging;
+pub mod maturity;
+pub mod merger_conf;
+pub mod metrics;
+pub mod notary_keys;
+pub mod notary_rpc;
+pub mod offline;
+pub mod reload;
+pub mod rpc_failover;
+pub mod scheduler;
+pub mod sent_txs;
+pub mod tx_builder;
+
+use coins::utxo::utxo_standard::{utxo_standard_coin_from_conf_and_request, UtxoStandardCoin};
+use common::block_on;
+use common::mm_ctx::MmArc;
+use common::serde_json::{self as json, Value as Json};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// The variants must never contain the seeds or the private keys, as the error is printed on exit.
+pub enum MainError {
+    ConfFileRead(std::io::Error),
+    ConfSerde(json::Error),
+    KeysError(keys::Error),
+    InvalidCliArg(String),
+    UnknownCoin(String),
+    String(String),
+}
+
+impl fmt::Debug for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MainError::ConfFileRead(e) => f.debug_tuple("ConfFileRead").field(e).finish(),
+            // the message of a JSON error may quote the config values, e.g. a seed of an invalid type
+            MainError::ConfSerde(e) => f
+                .debug_struct("ConfSerde")
+                .field("category", &e.classify())
+                .field("line", &e.line())
+                .field("column", &e.column())
+                .finish(),
+            MainError::KeysError(e) => f.debug_tuple("KeysError").field(e).finish(),
+            MainError::InvalidCliArg(e) => f.debug_tuple("InvalidCliArg").field(e).finish(),
+            MainError::UnknownCoin(e) => f.debug_tuple("UnknownCoin").field(e).finish(),
+            MainError::String(e) => f.debug_tuple("String").field(e).finish(),
+        }
+    }
+}
+
+impl From<std::io::Error> for MainError {
+    fn from(err: std::io::Error) -> MainError { MainError::ConfFileRead(err) }
+}
+
+impl From<json::Error> for MainError {
+    fn from(err: json::Error) -> MainError { MainError::ConfSerde(err) }
+}
+
+impl From<keys::Error> for MainError {
+    fn from(err: keys::Error) -> MainError { MainError::KeysError(err) }
+}
+
+impl From<String> for MainError {
+    fn from(err: String) -> MainError { MainError::String(err) }
+}
+
+/// Activates the coin with a dummy private key as the transactions are signed with the notary keys separately.
+pub fn activate_coin(
+    ctx: &MmArc,
+    ticker: &str,
+    mm_conf: &Json,
+    activation_command: &Json,
+) -> Result<UtxoStandardCoin, String> {
+    block_on(utxo_standard_coin_from_conf_and_request(
+        ctx,
+        ticker,
+        mm_conf,
+        activation_command,
+        &[1; 32],
+    ))
+}
+
+/// Reads and parses the config file, its content is wiped from memory as the config may still contain plaintext keys.
+pub fn read_config<T: DeserializeOwned>(path: &str) -> Result<T, MainError> {
+    let content = Zeroizing::new(std::fs::read_to_string(path)?);
+    Ok(json::from_str(&content)?)
+}
+
+/// Writes the `content` to a temporary file readable by the owner only and renames it to the `path`,
+/// so the file is never left partially written.
+pub fn write_atomically(path: &str, content: &[u8]) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+    write_private_file(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path).map_err(|e| format!("Error {} on renaming {}", e, tmp_path))
+}
+
+#[cfg(unix)]
+fn write_private_file(path: &str, content: &[u8]) -> Result<(), String> {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("Error {} on opening {}", e, path))?;
+    file.write_all(content)
+        .map_err(|e| format!("Error {} on writing {}", e, path))
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &str, content: &[u8]) -> Result<(), String> {
+    std::fs::write(path, content).map_err(|e| format!("Error {} on writing {}", e, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conf_serde_debug_hides_values() {
+        let seed = "test1 komodo dpow notary nodes";
+        let error = json::from_str::<Vec<String>>(&format!(r#""{}""#, seed)).unwrap_err();
+        assert!(error.to_string().contains(seed));
+        assert!(!format!("{:?}", MainError::from(error)).contains(seed));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::MainError;
+use std::fs::OpenOptions;
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per event including the fields of the current span, e.g. `ticker` and `pubkey`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", s)),
+        }
+    }
+}
+
+/// Installs the global subscriber writing to stdout or appending to the `log_file`.
+/// The returned guard flushes the buffered events on drop, so it must be kept until the process exits.
+pub fn init_logging(level: LevelFilter, format: LogFormat, log_file: Option<&str>) -> Result<WorkerGuard, MainError> {
+    let (writer, guard) = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            tracing_appender::non_blocking(file)
+        },
+        None => tracing_appender::non_blocking(std::io::stdout()),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer)
+        .with_ansi(log_file.is_none());
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init(),
+    }
+    Ok(guard)
+}
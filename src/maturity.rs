@@ -0,0 +1,160 @@
+use crate::notary_rpc::{NotaryRpcOps, NotaryUnspent};
+use common::serde_derive::Deserialize;
+use rpc::v1::types::H256 as H256Json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const DEFAULT_MATURITY_DEPTH: u64 = 100;
+
+fn default_maturity_depth() -> u64 { DEFAULT_MATURITY_DEPTH }
+
+/// The depth is the number of blocks mined on top of the block containing the unspent.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MaturityConf {
+    /// The depth required to spend coinbase outputs, e.g. the notary rewards on KMD.
+    #[serde(default = "default_maturity_depth")]
+    pub maturity_depth: u64,
+    /// Request the transactions of the unspents to check whether they are coinbase,
+    /// so regular outputs require only `regular_maturity_depth`.
+    /// Otherwise every unspent is treated as a coinbase output.
+    #[serde(default)]
+    pub distinguish_coinbase: bool,
+    #[serde(default)]
+    pub regular_maturity_depth: u64,
+}
+
+pub struct Maturity {
+    conf: MaturityConf,
+    /// Whether the transaction is coinbase along with its height, the status never changes so it's requested once.
+    /// The transactions deeper than `maturity_depth` are mature without the lookup, so they are evicted.
+    coinbase_txs: Mutex<HashMap<H256Json, (bool, u64)>>,
+}
+
+impl Maturity {
+    pub fn new(conf: MaturityConf) -> Maturity {
+        Maturity {
+            conf,
+            coinbase_txs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks whether the `unspent` can be spent on top of the `current_block`. Unconfirmed unspents are never mature.
+    pub fn is_mature(
+        &self,
+        rpc_client: &impl NotaryRpcOps,
+        unspent: &NotaryUnspent,
+        current_block: u64,
+    ) -> Result<bool, String> {
+        let depth = match unspent.height {
+            Some(height) if !unspent.in_mempool() => current_block.saturating_sub(height),
+            _ => return Ok(false),
+        };
+        if depth > self.conf.maturity_depth {
+            return Ok(true);
+        }
+        if !self.conf.distinguish_coinbase || depth <= self.conf.regular_maturity_depth {
+            return Ok(false);
+        }
+        Ok(!self.is_coinbase(rpc_client, unspent, current_block)?)
+    }
+
+    fn is_coinbase(
+        &self,
+        rpc_client: &impl NotaryRpcOps,
+        unspent: &NotaryUnspent,
+        current_block: u64,
+    ) -> Result<bool, String> {
+        if let Some((is_coinbase, _)) = self.coinbase_txs.lock().unwrap().get(&unspent.tx_hash) {
+            return Ok(*is_coinbase);
+        }
+        let is_coinbase = rpc_client.is_coinbase_tx(&unspent.tx_hash)?;
+        let height = unspent.height.unwrap_or_default();
+        let maturity_depth = self.conf.maturity_depth;
+        let mut coinbase_txs = self.coinbase_txs.lock().unwrap();
+        coinbase_txs.retain(|_, (_, tx_height)| current_block.saturating_sub(*tx_height) <= maturity_depth);
+        coinbase_txs.insert(unspent.tx_hash.clone(), (is_coinbase, height));
+        Ok(is_coinbase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notary_rpc::tests::TestRpc;
+    use crate::notary_rpc::SpendKind;
+    use std::sync::atomic::Ordering;
+
+    fn unspent(tx_hash: H256Json, height: Option<u64>) -> NotaryUnspent {
+        NotaryUnspent {
+            tx_hash,
+            tx_pos: 0,
+            value: 100_000,
+            height,
+            kind: SpendKind::P2pk,
+        }
+    }
+
+    #[test]
+    fn test_is_mature() {
+        let coinbase: H256Json = [1; 32].into();
+        let regular: H256Json = [2; 32].into();
+        let fresh: H256Json = [4; 32].into();
+        let rpc = TestRpc {
+            coinbase_txs: vec![
+                (coinbase.clone(), true),
+                (regular.clone(), false),
+                (fresh.clone(), false),
+            ]
+            .into_iter()
+            .collect(),
+            ..TestRpc::default()
+        };
+        let maturity = Maturity::new(MaturityConf {
+            maturity_depth: 100,
+            distinguish_coinbase: true,
+            regular_maturity_depth: 1,
+        });
+
+        assert!(!maturity.is_mature(&rpc, &unspent(regular.clone(), None), 1000).unwrap());
+        assert!(!maturity
+            .is_mature(&rpc, &unspent(regular.clone(), Some(0)), 1000)
+            .unwrap());
+        assert!(!maturity
+            .is_mature(&rpc, &unspent(regular.clone(), Some(999)), 1000)
+            .unwrap());
+        assert!(maturity
+            .is_mature(&rpc, &unspent(regular.clone(), Some(998)), 1000)
+            .unwrap());
+        assert!(!maturity
+            .is_mature(&rpc, &unspent(coinbase.clone(), Some(900)), 1000)
+            .unwrap());
+        assert!(maturity
+            .is_mature(&rpc, &unspent(coinbase.clone(), Some(899)), 1000)
+            .unwrap());
+        assert!(maturity
+            .is_mature(&rpc, &unspent(regular.clone(), Some(990)), 1000)
+            .unwrap());
+        // the deep enough unspents are not looked up, the coinbase status is cached
+        assert_eq!(rpc.coinbase_lookups.load(Ordering::SeqCst), 2);
+        assert!(maturity
+            .is_mature(&rpc, &unspent([3; 32].into(), Some(950)), 1000)
+            .is_err());
+
+        // the cached transactions are evicted once they are mature regardless of the coinbase status
+        assert_eq!(maturity.coinbase_txs.lock().unwrap().len(), 2);
+        assert!(maturity.is_mature(&rpc, &unspent(fresh, Some(1090)), 1100).unwrap());
+        assert_eq!(maturity.coinbase_txs.lock().unwrap().len(), 1);
+
+        let every_coinbase = Maturity::new(MaturityConf {
+            maturity_depth: 100,
+            distinguish_coinbase: false,
+            regular_maturity_depth: 0,
+        });
+        assert!(!every_coinbase
+            .is_mature(&rpc, &unspent(regular.clone(), Some(950)), 1000)
+            .unwrap());
+        assert!(every_coinbase
+            .is_mature(&rpc, &unspent(regular, Some(899)), 1000)
+            .unwrap());
+    }
+}
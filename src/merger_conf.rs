@@ -0,0 +1,269 @@
+use crate::alerts::AlertsConf;
+use crate::cli::retain_coins;
+use crate::fee::{FeePolicy, FeeSettings};
+use crate::keystore::KeysConf;
+use crate::maturity::MaturityConf;
+use crate::notary_keys::{wif_prefix, NotaryKeyPair};
+use crate::rpc_failover::RetryConf;
+use crate::{read_config, MainError};
+use common::serde_derive::Deserialize;
+use common::serde_json::Value as Json;
+use keys::Public;
+
+const DEFAULT_STATE_PATH: &str = "./merger_state.json";
+const DEFAULT_MIN_INPUTS: usize = 4;
+const DEFAULT_MAX_TX_INPUTS: usize = 500;
+/// The standard transaction size limit of the Komodo and Bitcoin daemons.
+const DEFAULT_MAX_TX_SIZE: usize = 100_000;
+const DEFAULT_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_MAX_CONCURRENT_COINS: usize = 4;
+const DEFAULT_JITTER_SECS: u64 = 30;
+
+fn default_state_path() -> String { DEFAULT_STATE_PATH.to_owned() }
+
+fn default_min_inputs() -> usize { DEFAULT_MIN_INPUTS }
+
+fn default_max_tx_inputs() -> usize { DEFAULT_MAX_TX_INPUTS }
+
+fn default_max_tx_size() -> usize { DEFAULT_MAX_TX_SIZE }
+
+fn default_timeout_secs() -> u64 { DEFAULT_TIMEOUT_SECS }
+
+fn default_max_concurrent_coins() -> usize { DEFAULT_MAX_CONCURRENT_COINS }
+
+fn default_jitter_secs() -> u64 { DEFAULT_JITTER_SECS }
+
+fn default_header_subscription() -> bool { true }
+
+#[derive(Debug, Deserialize)]
+pub struct CoinConf {
+    pub ticker: String,
+    pub activation_command: Json,
+    pub output_threshold: u64,
+    pub mm_conf: Json,
+    #[serde(flatten)]
+    pub maturity: MaturityConf,
+    /// The merge transaction is not sent until there are at least this number of eligible unspents.
+    #[serde(default = "default_min_inputs")]
+    pub min_inputs: usize,
+    /// Defaults to `txfee` of the `mm_conf` per kB.
+    #[serde(default)]
+    pub fee_policy: Option<FeePolicy>,
+    /// The upper limit of the fee paid by a single merge transaction.
+    #[serde(default)]
+    pub max_fee: Option<u64>,
+    /// Eligible unspents are split into several merge transactions with at most this number of inputs.
+    #[serde(default = "default_max_tx_inputs")]
+    pub max_tx_inputs: usize,
+    /// The size limit of a single signed merge transaction in bytes.
+    #[serde(default = "default_max_tx_size")]
+    pub max_tx_size: usize,
+    /// Overrides the global `send_to_address`, must belong to the network of the coin.
+    #[serde(default)]
+    pub send_to_address: Option<String>,
+    /// The merge of the coin is abandoned after this number of seconds, so it doesn't delay the next cycle.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// The delay between the merges of the coin, defaults to `--interval`.
+    /// Used only while the block header subscription is not available.
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// Merge when the tip advances according to the Electrum header subscription, enabled by default.
+    /// Requires TCP Electrum servers, the subscription connection doesn't support SSL.
+    #[serde(default = "default_header_subscription")]
+    pub header_subscription: bool,
+    /// Alert once a pubkey has fewer P2PK unspents below the `output_threshold`, not checked if not set.
+    #[serde(default)]
+    pub min_notarization_utxos: Option<usize>,
+    /// Alert on sending a merge transaction with the output of at least this number of satoshis.
+    #[serde(default)]
+    pub large_merge_value: Option<u64>,
+    /// The retries of the RPC calls failing over between the Electrum servers of the `activation_command`.
+    #[serde(default)]
+    pub retry: RetryConf,
+}
+
+impl CoinConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_inputs == 0 || self.max_tx_inputs == 0 || self.max_tx_size == 0 || self.timeout_secs == 0 {
+            return Err(format!(
+                "{} min_inputs, max_tx_inputs, max_tx_size and timeout_secs must be greater than 0",
+                self.ticker
+            ));
+        }
+        if self.interval_secs == Some(0) {
+            return Err(format!("{} interval_secs must be greater than 0", self.ticker));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(format!("{} retry max_attempts must be greater than 0", self.ticker));
+        }
+        Ok(())
+    }
+
+    pub fn send_to_address<'a>(&'a self, default: Option<&'a String>) -> Result<&'a str, String> {
+        self.send_to_address
+            .as_ref()
+            .or(default)
+            .map(String::as_str)
+            .ok_or_else(|| format!("{} send_to_address is not set", self.ticker))
+    }
+
+    /// The configured `fee_policy`, `txfee` of the `mm_conf` per kB if it's not set.
+    pub fn fee_settings(&self) -> FeeSettings {
+        let fee_policy = self
+            .fee_policy
+            .clone()
+            .unwrap_or_else(|| FeePolicy::from_mm_conf(&self.mm_conf));
+        FeeSettings::new(fee_policy, self.max_fee)
+    }
+}
+
+/// The config of `utxo_merger`, also read by `notary_status` to report the balances of the same coins.
+#[derive(Debug, Deserialize)]
+pub struct MergerConfig {
+    #[serde(flatten)]
+    pub keys: KeysConf,
+    /// The default destination of the coins not setting their own `send_to_address`.
+    #[serde(default)]
+    pub send_to_address: Option<String>,
+    pub coins: Vec<CoinConf>,
+    /// The file keeping the broadcast merge transactions between the cycles and restarts.
+    #[serde(default = "default_state_path")]
+    pub state_path: String,
+    /// The `host:port` to serve the Prometheus metrics at, the metrics are not served if not set.
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+    /// Hex encoded public keys the merges are planned for by `utxo_merger plan`, so the keys are not needed.
+    /// `notary_status` reports the balances of these keys if set, the keystore is not opened then.
+    /// The key in `merger.json.example` is the secp256k1 generator point, replace it with the notary pubkeys.
+    #[serde(default)]
+    pub pubkeys: Vec<String>,
+    /// The number of coins merged at the same time.
+    #[serde(default = "default_max_concurrent_coins")]
+    pub max_concurrent_coins: usize,
+    /// Up to this number of seconds is added to the interval of every merge randomly,
+    /// so the coins sharing the Electrum servers are not merged at the same time.
+    #[serde(default = "default_jitter_secs")]
+    pub jitter_secs: u64,
+    /// The loopback `host:port` to serve the control API at, the API is not served if not set.
+    #[serde(default)]
+    pub control_addr: Option<String>,
+    #[serde(default)]
+    pub alerts: AlertsConf,
+}
+
+/// The settings applied only on start, a reload changing them is warned about.
+#[derive(Clone, PartialEq)]
+pub struct StartupSettings {
+    pub state_path: String,
+    pub metrics_addr: Option<String>,
+    pub control_addr: Option<String>,
+    pub max_concurrent_coins: usize,
+    pub alerts: AlertsConf,
+}
+
+impl MergerConfig {
+    pub fn load(path: &str) -> Result<MergerConfig, MainError> { read_config(path) }
+
+    /// Checks the settings of the merger and every coin, so an invalid config fails before any coin is activated.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent_coins == 0 {
+            return Err("max_concurrent_coins must be greater than 0".into());
+        }
+        for coin in self.coins.iter() {
+            coin.validate()?;
+            coin.send_to_address(self.send_to_address.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Leaves only the `selected` coins, e.g. given with `--coin`, all the coins if it's empty.
+    pub fn retain_coins(&mut self, selected: &[&str]) -> Result<(), MainError> {
+        retain_coins(&mut self.coins, selected, |coin| coin.ticker.as_str())
+    }
+
+    pub fn startup_settings(&self) -> StartupSettings {
+        StartupSettings {
+            state_path: self.state_path.clone(),
+            metrics_addr: self.metrics_addr.clone(),
+            control_addr: self.control_addr.clone(),
+            max_concurrent_coins: self.max_concurrent_coins,
+            alerts: self.alerts.clone(),
+        }
+    }
+
+    /// Derives the notary keys, a WIF key must match the `wiftype` of one of the coins.
+    pub fn key_pairs(&mut self, passphrase_stdin: bool) -> Result<Vec<NotaryKeyPair>, MainError> {
+        let wif_prefixes: Vec<_> = self
+            .coins
+            .iter()
+            .map(|coin| (coin.ticker.as_str(), wif_prefix(&coin.mm_conf)))
+            .collect();
+        self.keys.key_pairs(passphrase_stdin, &wif_prefixes)
+    }
+
+    /// Parses the hex encoded `pubkeys`.
+    pub fn parse_pubkeys(&self) -> Result<Vec<Public>, MainError> {
+        self.pubkeys
+            .iter()
+            .map(|pubkey| {
+                let bytes = hex::decode(pubkey).map_err(|e| format!("Invalid pubkey {}: {}", pubkey, e))?;
+                Ok(Public::from_slice(&bytes)?)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::serde_json as json;
+
+    #[test]
+    fn test_defaults() {
+        let conf: MergerConfig = json::from_str(
+            r#"{
+                "send_to_address": "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh",
+                "pubkeys": ["02a854251adfee222bede8396fed0756985d4ea905f72611740867c7a4ad6488c1"],
+                "coins": [{"ticker": "RICK", "activation_command": {}, "output_threshold": 10000, "mm_conf": {}}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(conf.state_path, DEFAULT_STATE_PATH);
+        assert_eq!(conf.max_concurrent_coins, DEFAULT_MAX_CONCURRENT_COINS);
+        assert_eq!(conf.parse_pubkeys().unwrap().len(), 1);
+        conf.validate().unwrap();
+
+        let coin = &conf.coins[0];
+        coin.validate().unwrap();
+        assert_eq!(coin.min_inputs, DEFAULT_MIN_INPUTS);
+        assert!(coin.header_subscription);
+        assert_eq!(
+            coin.send_to_address(conf.send_to_address.as_ref()).unwrap(),
+            "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh"
+        );
+        assert!(coin.send_to_address(None).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut conf: MergerConfig = json::from_str(
+            r#"{
+                "coins": [{"ticker": "RICK", "activation_command": {}, "output_threshold": 10000, "mm_conf": {}}],
+                "max_concurrent_coins": 0
+            }"#,
+        )
+        .unwrap();
+        let error = conf.validate().unwrap_err();
+        assert!(error.contains("max_concurrent_coins"), "{}", error);
+
+        conf.max_concurrent_coins = 1;
+        let error = conf.validate().unwrap_err();
+        assert!(error.contains("send_to_address is not set"), "{}", error);
+
+        conf.send_to_address = Some("RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh".into());
+        conf.validate().unwrap();
+        conf.coins[0].min_inputs = 0;
+        assert!(conf.validate().is_err());
+    }
+}
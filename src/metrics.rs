@@ -0,0 +1,207 @@
+use common::now_ms;
+use prometheus::{Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+                 TextEncoder};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Response, Server};
+use tracing::{error, info};
+
+/// The merger metrics exported in the Prometheus text format.
+pub struct MergerMetrics {
+    registry: Registry,
+    pub eligible_unspents: IntGaugeVec,
+    /// In satoshis.
+    pub eligible_value: IntGaugeVec,
+    pub merges_sent: IntCounterVec,
+    pub merge_failures: IntCounterVec,
+    /// The merges skipped as the previous merge of the coin is still running, these are not failures.
+    pub merges_skipped: IntCounterVec,
+    /// UNIX timestamp of the last cycle that completed without errors.
+    pub last_success: IntGaugeVec,
+    pub block_height: IntGaugeVec,
+    pub rpc_latency: HistogramVec,
+}
+
+impl MergerMetrics {
+    pub fn new() -> Result<MergerMetrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some("utxo_merger".into()), None)?;
+
+        let eligible_unspents = IntGaugeVec::new(
+            Opts::new("eligible_unspents", "Number of unspents eligible for merging"),
+            &["ticker"],
+        )?;
+        registry.register(Box::new(eligible_unspents.clone()))?;
+
+        let eligible_value = IntGaugeVec::new(
+            Opts::new("eligible_value_sat", "Total value of the unspents eligible for merging"),
+            &["ticker"],
+        )?;
+        registry.register(Box::new(eligible_value.clone()))?;
+
+        let merges_sent = IntCounterVec::new(Opts::new("merges_sent_total", "Merge transactions sent"), &["ticker"])?;
+        registry.register(Box::new(merges_sent.clone()))?;
+
+        let merge_failures = IntCounterVec::new(
+            Opts::new("merge_failures_total", "Merge failures by the error kind"),
+            &["ticker", "kind"],
+        )?;
+        registry.register(Box::new(merge_failures.clone()))?;
+
+        let merges_skipped = IntCounterVec::new(
+            Opts::new(
+                "merges_skipped_total",
+                "Merges skipped as the previous one is still running",
+            ),
+            &["ticker"],
+        )?;
+        registry.register(Box::new(merges_skipped.clone()))?;
+
+        let last_success = IntGaugeVec::new(
+            Opts::new(
+                "last_success_timestamp_seconds",
+                "UNIX timestamp of the last cycle completed without errors",
+            ),
+            &["ticker"],
+        )?;
+        registry.register(Box::new(last_success.clone()))?;
+
+        let block_height = IntGaugeVec::new(Opts::new("block_height", "Current block height"), &["ticker"])?;
+        registry.register(Box::new(block_height.clone()))?;
+
+        let rpc_latency = HistogramVec::new(
+            HistogramOpts::new("rpc_latency_seconds", "Latency of the coin RPC calls"),
+            &["ticker", "method"],
+        )?;
+        registry.register(Box::new(rpc_latency.clone()))?;
+
+        Ok(MergerMetrics {
+            registry,
+            eligible_unspents,
+            eligible_value,
+            merges_sent,
+            merge_failures,
+            merges_skipped,
+            last_success,
+            block_height,
+            rpc_latency,
+        })
+    }
+
+    /// Observes the latency of the RPC call when dropped.
+    pub fn rpc_timer(&self, ticker: &str, method: &str) -> HistogramTimer {
+        self.rpc_latency.with_label_values(&[ticker, method]).start_timer()
+    }
+
+    pub fn merge_failed(&self, ticker: &str, kind: &str) {
+        self.merge_failures.with_label_values(&[ticker, kind]).inc()
+    }
+
+    pub fn merge_skipped(&self, ticker: &str) { self.merges_skipped.with_label_values(&[ticker]).inc() }
+
+    pub fn cycle_succeeded(&self, ticker: &str) {
+        self.last_success
+            .with_label_values(&[ticker])
+            .set((now_ms() / 1000) as i64)
+    }
+
+    /// Removes the gauges of a coin that is no longer merged, so its stale values are not exported.
+    pub fn remove_coin(&self, ticker: &str) {
+        for gauge in [
+            &self.eligible_unspents,
+            &self.eligible_value,
+            &self.last_success,
+            &self.block_height,
+        ]
+        .iter()
+        {
+            // fails if the coin hasn't set the gauge yet
+            let _ = gauge.remove_label_values(&[ticker]);
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Serves the metrics over HTTP at the `addr` from a background thread.
+    pub fn serve(self: Arc<Self>, addr: &str) -> Result<(), String> {
+        let server =
+            Server::http(addr).map_err(|e| format!("Error {} on binding the metrics server to {}", e, addr))?;
+        let content_type = Header::from_bytes(&b"Content-Type"[..], TextEncoder::new().format_type().as_bytes())
+            .map_err(|_| "Invalid Content-Type header".to_owned())?;
+        info!(%addr, "Serving metrics");
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let result = match self.encode() {
+                    Ok(body) => request.respond(Response::from_data(body).with_header(content_type.clone())),
+                    Err(e) => {
+                        error!(error = %e, "Failed to encode the metrics");
+                        request.respond(Response::empty(500))
+                    },
+                };
+                if let Err(e) = result {
+                    error!(error = %e, "Failed to respond to the metrics request");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_merge_cycle() {
+        let metrics = MergerMetrics::new().unwrap();
+        // the updates of one cycle merging RICK and failing to send the MORTY merge
+        metrics.block_height.with_label_values(&["RICK"]).set(1000);
+        metrics.eligible_unspents.with_label_values(&["RICK"]).set(3);
+        metrics.eligible_value.with_label_values(&["RICK"]).set(30_000);
+        metrics.rpc_timer("RICK", "send_raw_tx").observe_duration();
+        metrics.merges_sent.with_label_values(&["RICK"]).inc();
+        metrics.cycle_succeeded("RICK");
+        metrics.merge_failed("MORTY", "send");
+        metrics.merge_skipped("MORTY");
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        for expected in [
+            r#"utxo_merger_block_height{ticker="RICK"} 1000"#,
+            r#"utxo_merger_eligible_unspents{ticker="RICK"} 3"#,
+            r#"utxo_merger_eligible_value_sat{ticker="RICK"} 30000"#,
+            r#"utxo_merger_merges_sent_total{ticker="RICK"} 1"#,
+            r#"utxo_merger_merge_failures_total{kind="send",ticker="MORTY"} 1"#,
+            r#"utxo_merger_merges_skipped_total{ticker="MORTY"} 1"#,
+            r#"utxo_merger_rpc_latency_seconds_count{method="send_raw_tx",ticker="RICK"} 1"#,
+        ]
+        .iter()
+        {
+            assert!(lines.contains(expected), "{} is not in\n{}", expected, text);
+        }
+        let last_success = lines
+            .iter()
+            .find_map(|line| line.strip_prefix(r#"utxo_merger_last_success_timestamp_seconds{ticker="RICK"} "#))
+            .unwrap();
+        assert!(last_success.parse::<i64>().unwrap() > 0);
+
+        // the gauges of a removed coin are not exported, the counters are kept
+        metrics.remove_coin("RICK");
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert!(!text.contains(r#"utxo_merger_block_height{ticker="RICK"}"#), "{}", text);
+        assert!(
+            !text.contains(r#"utxo_merger_last_success_timestamp_seconds{ticker="RICK"}"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(r#"utxo_merger_merges_sent_total{ticker="RICK"} 1"#),
+            "{}",
+            text
+        );
+    }
+}
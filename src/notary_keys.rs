@@ -0,0 +1,180 @@
+use crate::MainError;
+use common::privkey::key_pair_from_seed;
+use common::serde_derive::{Deserialize, Serialize};
+use common::serde_json::Value as Json;
+use keys::{KeyPair, Private};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The `wiftype` used by the coins that don't set it.
+const DEFAULT_WIF_PREFIX: u8 = 128;
+/// Replaces the wiped private keys, any valid secp256k1 secret key would do.
+const WIPED_SECRET: [u8; 32] = [1; 32];
+
+/// The notary key pair that overwrites its private key on drop.
+/// `Debug` shows the public key only, so the key pair can be logged safely.
+pub struct NotaryKeyPair(KeyPair);
+
+impl NotaryKeyPair {
+    pub fn new(keypair: KeyPair) -> NotaryKeyPair { NotaryKeyPair(keypair) }
+}
+
+impl Deref for NotaryKeyPair {
+    type Target = KeyPair;
+
+    fn deref(&self) -> &KeyPair { &self.0 }
+}
+
+impl fmt::Debug for NotaryKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("NotaryKeyPair").field(self.0.public()).finish()
+    }
+}
+
+impl Drop for NotaryKeyPair {
+    fn drop(&mut self) {
+        let wiped = Private {
+            prefix: 0,
+            secret: WIPED_SECRET.into(),
+            compressed: true,
+        };
+        if let Ok(wiped) = KeyPair::from_private(wiped) {
+            // the volatile write overwrites the secret in place and can't be optimized out as a dead store
+            unsafe { std::ptr::write_volatile(&mut self.0, wiped) }
+        }
+    }
+}
+
+/// The `wiftype` of the coin config.
+pub fn wif_prefix(mm_conf: &Json) -> u8 {
+    mm_conf["wiftype"]
+        .as_u64()
+        .map(|wiftype| wiftype as u8)
+        .unwrap_or(DEFAULT_WIF_PREFIX)
+}
+
+/// A notary private key in one of the supported formats, wiped from memory on drop.
+/// Not `Clone`, so the key is moved rather than copied around.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeyEntry {
+    /// The brain wallet passphrase, the same as the legacy `seeds`.
+    Passphrase { passphrase: String },
+    /// The WIF export of `dumpprivkey`, its prefix must match the `wiftype` of one of the processed coins.
+    /// The prefix is not a part of the key, so the same key signs for the coins with the other `wiftype`s.
+    Wif { wif: String },
+    /// The hex encoded 32 bytes secret, the public key is compressed.
+    PrivKey { privkey: String },
+}
+
+impl KeyEntry {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KeyEntry::Passphrase { .. } => "passphrase",
+            KeyEntry::Wif { .. } => "wif",
+            KeyEntry::PrivKey { .. } => "privkey",
+        }
+    }
+
+    /// `wif_prefixes` are the `wiftype`s of the processed coins by the ticker.
+    pub fn key_pair(&self, wif_prefixes: &[(&str, u8)]) -> Result<NotaryKeyPair, MainError> {
+        let keypair = match self {
+            KeyEntry::Passphrase { passphrase } => key_pair_from_seed(passphrase)?,
+            KeyEntry::Wif { wif } => {
+                let private = Private::from_str(wif)?;
+                if !wif_prefixes.is_empty() && !wif_prefixes.iter().any(|(_, wiftype)| *wiftype == private.prefix) {
+                    let wiftypes: Vec<_> = wif_prefixes
+                        .iter()
+                        .map(|(ticker, wiftype)| format!("{} {}", ticker, wiftype))
+                        .collect();
+                    return Err(MainError::String(format!(
+                        "WIF prefix {} doesn't match the wiftype of any coin: {}",
+                        private.prefix,
+                        wiftypes.join(", ")
+                    )));
+                }
+                KeyPair::from_private(private)?
+            },
+            KeyEntry::PrivKey { privkey } => {
+                let bytes =
+                    Zeroizing::new(hex::decode(privkey).map_err(|_| MainError::String("Invalid hex privkey".into()))?);
+                if bytes.len() != 32 {
+                    return Err(MainError::String(format!("Invalid privkey length {}", bytes.len())));
+                }
+                let mut secret = [0; 32];
+                secret.copy_from_slice(&bytes);
+                let private = Private {
+                    prefix: 0,
+                    secret: secret.into(),
+                    compressed: true,
+                };
+                secret.zeroize();
+                KeyPair::from_private(private)?
+            },
+        };
+        Ok(NotaryKeyPair::new(keypair))
+    }
+}
+
+impl fmt::Debug for KeyEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "KeyEntry({}, redacted)", self.kind()) }
+}
+
+impl Zeroize for KeyEntry {
+    fn zeroize(&mut self) {
+        match self {
+            KeyEntry::Passphrase { passphrase } => passphrase.zeroize(),
+            KeyEntry::Wif { wif } => wif.zeroize(),
+            KeyEntry::PrivKey { privkey } => privkey.zeroize(),
+        }
+    }
+}
+
+impl Drop for KeyEntry {
+    fn drop(&mut self) { self.zeroize() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_hides_private_key() {
+        let keypair = NotaryKeyPair::new(key_pair_from_seed("test1 komodo dpow notary nodes").unwrap());
+        let private = keypair.private().to_string();
+        let secret = hex::encode(&*keypair.private().secret);
+
+        let debug = format!("{:?}", keypair);
+        assert!(debug.contains(&keypair.public().to_string()));
+        assert!(!debug.contains(&private));
+        assert!(!debug.contains(&secret));
+    }
+
+    #[test]
+    fn test_key_entry_formats_give_same_key_pair() {
+        let from_seed = key_pair_from_seed("test1 komodo dpow notary nodes").unwrap();
+        let mut private = from_seed.private().clone();
+        private.prefix = 188;
+
+        let wif = KeyEntry::Wif {
+            wif: private.to_string(),
+        };
+        let keypair = wif.key_pair(&[("KMD", 188)]).unwrap();
+        assert_eq!(keypair.public(), from_seed.public());
+
+        let privkey = KeyEntry::PrivKey {
+            privkey: hex::encode(&*private.secret),
+        };
+        let keypair = privkey.key_pair(&[("KMD", 188)]).unwrap();
+        assert_eq!(keypair.public(), from_seed.public());
+
+        let keypair = wif.key_pair(&[("KMD", 188), ("BTC", 128)]).unwrap();
+        assert_eq!(keypair.public(), from_seed.public());
+
+        let error = wif.key_pair(&[("BTC", 128), ("LTC", 176)]).unwrap_err();
+        assert!(!format!("{:?}", error).contains(&private.to_string()));
+        assert!(!format!("{:?}", wif).contains(&private.to_string()));
+    }
+}
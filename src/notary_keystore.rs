@@ -0,0 +1,185 @@
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind as ClapErrorKind, SubCommand};
+use common::mm_error::prelude::*;
+use notary_tools_rust::keystore::{create_keystore, load_keys, read_passphrase, read_passphrase_with, read_secret,
+                                  save_keys};
+use notary_tools_rust::notary_keys::KeyEntry;
+use notary_tools_rust::MainError;
+use zeroize::Zeroizing;
+
+const DEFAULT_KEYSTORE_PATH: &str = "./keystore.json";
+
+fn cli_app() -> App<'static, 'static> {
+    let key_stdin = Arg::with_name("key-stdin")
+        .long("key-stdin")
+        .help("Read the key from the line of stdin following the passphrase instead of prompting for it");
+    let key_type = Arg::with_name("type")
+        .long("type")
+        .value_name("TYPE")
+        .possible_values(&["passphrase", "wif", "privkey"])
+        .default_value("passphrase")
+        .help("The format of the added key: brain wallet passphrase, WIF or hex encoded private key");
+    App::new("notary_keystore")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Manages the encrypted keystore of the notary keys")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("keystore")
+                .short("k")
+                .long("keystore")
+                .value_name("PATH")
+                .default_value(DEFAULT_KEYSTORE_PATH)
+                .global(true)
+                .help("Path to the keystore file"),
+        )
+        .arg(
+            Arg::with_name("passphrase-stdin")
+                .long("passphrase-stdin")
+                .global(true)
+                .help("Read the passphrase from the first line of stdin instead of prompting for it"),
+        )
+        .subcommand(SubCommand::with_name("create").about("Creates an empty keystore"))
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Adds a key to the keystore")
+                .arg(key_type)
+                .arg(key_stdin),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("Removes the key of the public key from the keystore")
+                .arg(
+                    Arg::with_name("pubkey")
+                        .value_name("PUBKEY")
+                        .required(true)
+                        .help("Hex encoded public key"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("list").about("Prints the types and the public keys of the stored keys"))
+}
+
+/// Asks for the passphrase twice unless it's given non-interactively.
+fn read_new_passphrase<F>(from_stdin: bool, read_secret: F) -> Result<Zeroizing<String>, MainError>
+where
+    F: Fn(&str, bool) -> Result<Zeroizing<String>, String>,
+{
+    let (passphrase, prompted) = read_passphrase_with(from_stdin, &read_secret)?;
+    if prompted {
+        let confirmation = read_secret("Repeat the passphrase: ", false)?;
+        if confirmation != passphrase {
+            return Err(MainError::String("Passphrases don't match".into()));
+        }
+    }
+    if passphrase.is_empty() {
+        return Err(MainError::String("Passphrase must not be empty".into()));
+    }
+    Ok(passphrase)
+}
+
+/// WIF prefixes are not checked as the keystore is not bound to the coins.
+fn pubkey_of(key: &KeyEntry) -> Result<String, MainError> { Ok(key.key_pair(&[])?.public().to_string()) }
+
+/// Moves the secret out of the wiped buffer into the `KeyEntry` wiping it in turn, so the secret is never copied.
+fn take_secret(mut secret: Zeroizing<String>) -> String { std::mem::take(&mut *secret) }
+
+fn read_key(key_type: &str, from_stdin: bool) -> Result<KeyEntry, MainError> {
+    let key = match key_type {
+        "passphrase" => KeyEntry::Passphrase {
+            passphrase: take_secret(read_secret("Seed passphrase: ", from_stdin)?),
+        },
+        "wif" => KeyEntry::Wif {
+            wif: take_secret(read_secret("WIF: ", from_stdin)?),
+        },
+        "privkey" => KeyEntry::PrivKey {
+            privkey: take_secret(read_secret("Hex private key: ", from_stdin)?),
+        },
+        _ => return Err(MainError::InvalidCliArg(format!("Unknown key type {}", key_type))),
+    };
+    Ok(key)
+}
+
+fn run(matches: &ArgMatches) -> Result<(), MainError> {
+    let path = matches.value_of("keystore").unwrap_or(DEFAULT_KEYSTORE_PATH);
+    let passphrase_stdin = matches.is_present("passphrase-stdin");
+
+    match matches.subcommand() {
+        ("create", Some(_)) => {
+            let passphrase = read_new_passphrase(passphrase_stdin, read_secret)?;
+            create_keystore(path, &passphrase)?;
+            println!("Created {}", path);
+        },
+        ("add", Some(sub)) => {
+            let passphrase = read_passphrase(passphrase_stdin)?;
+            let mut keys = load_keys(path, &passphrase)?;
+            let key = read_key(
+                sub.value_of("type").unwrap_or("passphrase"),
+                sub.is_present("key-stdin"),
+            )?;
+            let pubkey = pubkey_of(&key)?;
+            for existing in keys.iter() {
+                if pubkey_of(existing)? == pubkey {
+                    return Err(MainError::String(format!("The key of {} is already stored", pubkey)));
+                }
+            }
+            keys.push(key);
+            save_keys(path, &passphrase, &keys)?;
+            println!("Added {}", pubkey);
+        },
+        ("remove", Some(sub)) => {
+            let pubkey = sub.value_of("pubkey").unwrap_or_default();
+            let passphrase = read_passphrase(passphrase_stdin)?;
+            let mut keys = load_keys(path, &passphrase)?;
+            let pubkeys = keys.iter().map(pubkey_of).collect::<Result<Vec<_>, _>>()?;
+            let count = keys.len();
+            let mut pubkeys = pubkeys.iter();
+            // the removed keys are wiped on drop
+            keys.retain(|_| pubkeys.next().map_or(true, |key_pubkey| key_pubkey != pubkey));
+            if keys.len() == count {
+                return Err(MainError::String(format!("No key of {} is stored", pubkey)));
+            }
+            save_keys(path, &passphrase, &keys)?;
+            println!("Removed {}", pubkey);
+        },
+        ("list", Some(_)) => {
+            let passphrase = read_passphrase(passphrase_stdin)?;
+            for key in load_keys(path, &passphrase)?.iter() {
+                println!("{} {}", key.kind(), pubkey_of(key)?);
+            }
+        },
+        _ => unreachable!("SubcommandRequiredElseHelp"),
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), MmError<MainError>> {
+    let matches = match cli_app().get_matches_safe() {
+        Ok(m) => m,
+        Err(e) => match e.kind {
+            ClapErrorKind::HelpDisplayed | ClapErrorKind::VersionDisplayed => e.exit(),
+            _ => return Err(MainError::InvalidCliArg(e.message).into()),
+        },
+    };
+    run(&matches)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notary_tools_rust::keystore::PASSPHRASE_ENV;
+
+    #[test]
+    fn test_new_passphrase() {
+        std::env::set_var(PASSPHRASE_ENV, "env passphrase");
+        let passphrase = read_new_passphrase(false, |prompt, _| Err(format!("Unexpected prompt {}", prompt))).unwrap();
+        assert_eq!(passphrase.as_str(), "env passphrase");
+        assert!(std::env::var(PASSPHRASE_ENV).is_err());
+
+        let prompts = std::cell::Cell::new(0);
+        let read_twice = |_: &str, _| {
+            prompts.set(prompts.get() + 1);
+            Ok(Zeroizing::new(format!("typed passphrase {}", prompts.get())))
+        };
+        assert!(read_new_passphrase(false, read_twice).is_err());
+        assert_eq!(prompts.get(), 2);
+    }
+}
@@ -0,0 +1,476 @@
+use crate::sent_txs::SpentOutpoint;
+use chain::constants::SEQUENCE_FINAL;
+use chain::OutPoint;
+use coins::utxo::rpc_clients::{electrum_script_hash, EstimateFeeMethod, NativeUnspent, UtxoRpcClientEnum,
+                               UtxoRpcClientOps};
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use coins::utxo::{address_from_raw_pubkey, sat_from_big_decimal, Address, UtxoTx};
+use common::serde_derive::{Deserialize, Serialize};
+use futures01::Future;
+use keys::Public;
+use rpc::v1::types::H256 as H256Json;
+use script::{Builder, Script, UnsignedTransactionInput};
+use serialization::deserialize;
+use std::collections::HashSet;
+
+/// The upper bound of confirmations passed to the `listunspent` of the native daemon.
+const NATIVE_MAX_CONF: u64 = 99_999_999;
+
+/// The largest DER signature followed by the sighash type.
+const MAX_SIGNATURE_SIZE: usize = 73;
+
+/// The start of the JSON-RPC error object in the body of the failed HTTP request to the native daemon.
+const NATIVE_RPC_ERROR: &str = "\"error\":{\"code\":";
+
+/// The errors of the daemon looking up a transaction that is neither in the mempool nor in the chain,
+/// relayed by the Electrum servers as well.
+const TX_NOT_FOUND_ERRORS: [&str; 2] = [
+    "no such mempool or blockchain transaction",
+    "no information available about transaction",
+];
+
+/// The script a notary key output is locked by.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpendKind {
+    P2pk,
+    P2pkh,
+}
+
+impl Default for SpendKind {
+    fn default() -> SpendKind { SpendKind::P2pk }
+}
+
+impl SpendKind {
+    pub const ALL: [SpendKind; 2] = [SpendKind::P2pk, SpendKind::P2pkh];
+
+    pub fn script(self, public: &Public) -> Script {
+        match self {
+            SpendKind::P2pk => Builder::build_p2pk(public),
+            SpendKind::P2pkh => Builder::build_p2pkh(&public.address_hash()),
+        }
+    }
+
+    /// The largest scriptSig spending the output of the `public` key, used to estimate the size of unsigned txs.
+    pub fn max_script_sig_size(self, public: &Public) -> usize {
+        let signature_push = 1 + MAX_SIGNATURE_SIZE;
+        match self {
+            SpendKind::P2pk => signature_push,
+            SpendKind::P2pkh => signature_push + 1 + public.len(),
+        }
+    }
+}
+
+/// The unspent output of a notary key, the same for both Electrum and native RPC clients.
+#[derive(Clone, Debug)]
+pub struct NotaryUnspent {
+    pub tx_hash: H256Json,
+    pub tx_pos: u32,
+    pub value: u64,
+    /// `None` or `Some(0)` if the transaction is not confirmed yet, see `in_mempool`.
+    pub height: Option<u64>,
+    pub kind: SpendKind,
+}
+
+impl NotaryUnspent {
+    /// Electrum servers report the height of the mempool transactions as 0, the native RPC leaves it unset.
+    pub fn in_mempool(&self) -> bool { matches!(self.height, None | Some(0)) }
+
+    pub fn unsigned_input(&self) -> UnsignedTransactionInput {
+        UnsignedTransactionInput {
+            previous_output: OutPoint {
+                hash: self.tx_hash.reversed().into(),
+                index: self.tx_pos,
+            },
+            sequence: SEQUENCE_FINAL,
+            amount: self.value,
+        }
+    }
+}
+
+/// The subset of the coin RPC used by the notary tools, implemented for both Electrum and native clients.
+pub trait NotaryRpcOps {
+    fn block_count(&self) -> Result<u64, String>;
+
+    /// Lists the unspent outputs of the `public` key locked by the script of the `kind`.
+    /// The native daemon indexes both P2PK and P2PKH outputs of the pubkey by its `address`,
+    /// so the outputs are queried by `address` and then filtered by the script.
+    fn pubkey_unspents(
+        &self,
+        kind: SpendKind,
+        public: &Public,
+        address: &Address,
+        decimals: u8,
+        current_block: u64,
+    ) -> Result<Vec<NotaryUnspent>, String>;
+
+    /// Returns the hash of the broadcast transaction.
+    fn send_raw_tx(&self, tx: &[u8]) -> Result<String, String>;
+
+    fn is_coinbase_tx(&self, tx_hash: &H256Json) -> Result<bool, String>;
+
+    /// The serialized transaction, e.g. the previous transaction of an input written to the offline plan.
+    fn tx_bytes(&self, tx_hash: &H256Json) -> Result<Vec<u8>, String>;
+
+    /// Returns 0 if the transaction is in the mempool, fails if the transaction is not found.
+    fn tx_confirmations(&self, tx_hash: &H256Json) -> Result<u32, String>;
+
+    /// The outpoints spent by the mempool transactions touching the `kind` outputs of the `public` key,
+    /// so the unspents already being spent, e.g. by a notarization in flight, are not selected again.
+    /// Empty for the native daemon as its `listunspent` leaves out the outputs spent by the wallet mempool.
+    fn mempool_spent_outpoints(&self, kind: SpendKind, public: &Public) -> Result<HashSet<SpentOutpoint>, String>;
+
+    /// The fee rate in satoshis per kB expected to confirm a transaction within `n_blocks`.
+    fn estimate_fee_rate(&self, decimals: u8, n_blocks: u32) -> Result<u64, String>;
+}
+
+/// Whether the `error` was returned by the server, e.g. the transaction is rejected by the network rules
+/// or not found, rather than caused by a transport failure or a timeout, so another server would return the same.
+/// Electrum errors are the `Response` of the client error, the native daemon ones come as the JSON-RPC error
+/// in the body of the HTTP request failed with the status 500.
+pub fn is_rpc_rejection(error: &str) -> bool {
+    let error: String = error.chars().filter(|c| *c != '\\' && !c.is_whitespace()).collect();
+    error.contains("Response(") || error.contains(NATIVE_RPC_ERROR)
+}
+
+/// Whether the server answered the looked up transaction is not known, rather than failed to answer.
+pub fn is_tx_not_found(error: &str) -> bool {
+    let lowercase = error.to_lowercase();
+    is_rpc_rejection(error)
+        && TX_NOT_FOUND_ERRORS
+            .iter()
+            .any(|not_found| lowercase.contains(not_found))
+}
+
+/// The address of the `public` key with the coin prefixes.
+pub fn pubkey_address(coin: &UtxoStandardCoin, public: &Public) -> Result<Address, String> {
+    let conf = &coin.as_ref().conf;
+    address_from_raw_pubkey(public, conf.pub_addr_prefix, conf.pub_t_addr_prefix, conf.checksum_type)
+}
+
+/// Parses the `address` failing if its prefixes don't match the network of the coin.
+pub fn coin_address(coin: &UtxoStandardCoin, ticker: &str, address: &str) -> Result<Address, String> {
+    let conf = &coin.as_ref().conf;
+    address_with_prefixes(ticker, address, conf.pub_addr_prefix, conf.pub_t_addr_prefix)
+}
+
+/// Parses the `address` failing unless it has the prefixes of the coin, the `pubtype` and `taddr` of its `mm_conf`.
+fn address_with_prefixes(
+    ticker: &str,
+    address: &str,
+    pub_addr_prefix: u8,
+    pub_t_addr_prefix: u8,
+) -> Result<Address, String> {
+    let parsed: Address = address
+        .parse()
+        .map_err(|e| format!("Invalid {} address {}: {}", ticker, address, e))?;
+    if parsed.prefix != pub_addr_prefix || parsed.t_addr_prefix != pub_t_addr_prefix {
+        return Err(format!(
+            "Address {} doesn't belong to the {} network, expected prefixes {}/{}, got {}/{}",
+            address, ticker, pub_t_addr_prefix, pub_addr_prefix, parsed.t_addr_prefix, parsed.prefix
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Converts the `listunspent` entries of the pubkey address to the unspents locked by the `script` of the `kind`.
+/// The height of a confirmed entry is derived from its confirmations and the `current_block`.
+fn native_pubkey_unspents(
+    unspents: Vec<NativeUnspent>,
+    kind: SpendKind,
+    script: &Script,
+    decimals: u8,
+    current_block: u64,
+) -> Result<Vec<NotaryUnspent>, String> {
+    let script_bytes = script.to_vec();
+    unspents
+        .into_iter()
+        .filter(|unspent| unspent.script_pub_key.0 == script_bytes)
+        .map(|unspent| {
+            let height = match unspent.confirmations {
+                0 => None,
+                confirmations => Some((current_block + 1).saturating_sub(confirmations)),
+            };
+            Ok(NotaryUnspent {
+                tx_hash: unspent.txid,
+                tx_pos: unspent.vout,
+                value: sat_from_big_decimal(&unspent.amount.to_decimal(), decimals).map_err(|e| e.to_string())?,
+                height,
+                kind,
+            })
+        })
+        .collect()
+}
+
+impl NotaryRpcOps for UtxoRpcClientEnum {
+    fn block_count(&self) -> Result<u64, String> { self.get_block_count().wait().map_err(|e| e.to_string()) }
+
+    fn pubkey_unspents(
+        &self,
+        kind: SpendKind,
+        public: &Public,
+        address: &Address,
+        decimals: u8,
+        current_block: u64,
+    ) -> Result<Vec<NotaryUnspent>, String> {
+        let script = kind.script(public);
+        match self {
+            UtxoRpcClientEnum::Electrum(electrum) => {
+                let hash_str = hex::encode(electrum_script_hash(&script));
+                let unspents = electrum
+                    .scripthash_list_unspent(&hash_str)
+                    .wait()
+                    .map_err(|e| e.to_string())?;
+                Ok(unspents
+                    .into_iter()
+                    .map(|unspent| NotaryUnspent {
+                        tx_hash: unspent.tx_hash,
+                        tx_pos: unspent.tx_pos,
+                        value: unspent.value,
+                        height: unspent.height,
+                        kind,
+                    })
+                    .collect())
+            },
+            UtxoRpcClientEnum::Native(native) => {
+                let unspents = native
+                    .list_unspent_impl(0, NATIVE_MAX_CONF, vec![address.to_string()])
+                    .wait()
+                    .map_err(|e| e.to_string())?;
+                native_pubkey_unspents(unspents, kind, &script, decimals, current_block)
+            },
+        }
+    }
+
+    fn send_raw_tx(&self, tx: &[u8]) -> Result<String, String> {
+        self.send_raw_transaction(tx.to_vec().into())
+            .wait()
+            .map(|hash| format!("{:?}", hash))
+            .map_err(|e| e.to_string())
+    }
+
+    fn is_coinbase_tx(&self, tx_hash: &H256Json) -> Result<bool, String> {
+        let bytes = self.tx_bytes(tx_hash)?;
+        let tx: UtxoTx = deserialize(bytes.as_slice()).map_err(|e| format!("{:?}", e))?;
+        Ok(tx.is_coinbase())
+    }
+
+    fn tx_bytes(&self, tx_hash: &H256Json) -> Result<Vec<u8>, String> {
+        self.get_transaction_bytes(tx_hash.clone())
+            .wait()
+            .map(|bytes| bytes.0)
+            .map_err(|e| e.to_string())
+    }
+
+    fn tx_confirmations(&self, tx_hash: &H256Json) -> Result<u32, String> {
+        self.get_verbose_transaction(tx_hash.clone())
+            .wait()
+            .map(|tx| tx.confirmations)
+            .map_err(|e| e.to_string())
+    }
+
+    fn mempool_spent_outpoints(&self, kind: SpendKind, public: &Public) -> Result<HashSet<SpentOutpoint>, String> {
+        let electrum = match self {
+            UtxoRpcClientEnum::Electrum(electrum) => electrum,
+            UtxoRpcClientEnum::Native(_) => return Ok(HashSet::new()),
+        };
+        let hash_str = hex::encode(electrum_script_hash(&kind.script(public)));
+        let history = electrum
+            .scripthash_get_history(&hash_str)
+            .wait()
+            .map_err(|e| e.to_string())?;
+
+        let mut spent = HashSet::new();
+        // the mempool transactions have the height 0, or -1 if they spend other mempool transactions
+        for item in history.into_iter().filter(|item| item.height <= 0) {
+            let bytes = self
+                .get_transaction_bytes(item.tx_hash)
+                .wait()
+                .map_err(|e| e.to_string())?;
+            let tx: UtxoTx = deserialize(bytes.0.as_slice()).map_err(|e| format!("{:?}", e))?;
+            spent.extend(tx.inputs.iter().map(|input| SpentOutpoint {
+                tx_hash: input.previous_output.hash.reversed().into(),
+                tx_pos: input.previous_output.index,
+            }));
+        }
+        Ok(spent)
+    }
+
+    fn estimate_fee_rate(&self, decimals: u8, n_blocks: u32) -> Result<u64, String> {
+        self.estimate_fee_sat(decimals, &EstimateFeeMethod::Standard, &None, n_blocks)
+            .wait()
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use common::serde_json as json;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub(crate) const TX_NOT_FOUND: &str = "JsonRpcError { error: Response(Electrum(\"electrum1.cipig.net:10017\"), \
+                                           Object({\"code\": Number(2), \"message\": String(\"daemon error: \
+                                           No such mempool or blockchain transaction\")})) }";
+    pub(crate) const TIMED_OUT: &str = "JsonRpcError { error: Transport(\"Electrum request timed out\") }";
+
+    /// Answers the transaction lookups of the unit tests from the maps, the other calls fail.
+    #[derive(Default)]
+    pub(crate) struct TestRpc {
+        pub(crate) coinbase_txs: HashMap<H256Json, bool>,
+        pub(crate) confirmations: HashMap<H256Json, u32>,
+        /// The confirmations lookups of these transactions time out, the other unknown ones are not found.
+        pub(crate) unreachable_txs: HashSet<H256Json>,
+        pub(crate) coinbase_lookups: AtomicUsize,
+    }
+
+    impl NotaryRpcOps for TestRpc {
+        fn block_count(&self) -> Result<u64, String> { Err("Not supported".into()) }
+
+        fn pubkey_unspents(
+            &self,
+            _kind: SpendKind,
+            _public: &Public,
+            _address: &Address,
+            _decimals: u8,
+            _current_block: u64,
+        ) -> Result<Vec<NotaryUnspent>, String> {
+            Err("Not supported".into())
+        }
+
+        fn send_raw_tx(&self, _tx: &[u8]) -> Result<String, String> { Err("Not supported".into()) }
+
+        fn is_coinbase_tx(&self, tx_hash: &H256Json) -> Result<bool, String> {
+            self.coinbase_lookups.fetch_add(1, Ordering::SeqCst);
+            self.coinbase_txs
+                .get(tx_hash)
+                .copied()
+                .ok_or_else(|| "No such transaction".to_owned())
+        }
+
+        fn tx_bytes(&self, _tx_hash: &H256Json) -> Result<Vec<u8>, String> { Err("Not supported".into()) }
+
+        fn tx_confirmations(&self, tx_hash: &H256Json) -> Result<u32, String> {
+            if self.unreachable_txs.contains(tx_hash) {
+                return Err(TIMED_OUT.into());
+            }
+            self.confirmations
+                .get(tx_hash)
+                .copied()
+                .ok_or_else(|| TX_NOT_FOUND.to_owned())
+        }
+
+        fn mempool_spent_outpoints(
+            &self,
+            _kind: SpendKind,
+            _public: &Public,
+        ) -> Result<HashSet<SpentOutpoint>, String> {
+            Err("Not supported".into())
+        }
+
+        fn estimate_fee_rate(&self, _decimals: u8, _n_blocks: u32) -> Result<u64, String> {
+            Err("Not supported".into())
+        }
+    }
+
+    #[test]
+    fn test_in_mempool() {
+        let unspent = |height| NotaryUnspent {
+            tx_hash: H256Json::default(),
+            tx_pos: 0,
+            value: 1000,
+            height,
+            kind: SpendKind::P2pk,
+        };
+        assert!(unspent(None).in_mempool());
+        assert!(unspent(Some(0)).in_mempool());
+        assert!(!unspent(Some(1)).in_mempool());
+    }
+
+    #[test]
+    fn test_rpc_rejection() {
+        let electrum = "JsonRpcError { error: Response(Electrum(\"electrum1.cipig.net:10017\"), \
+                        Object({\"code\": Number(1), \"message\": String(\"bad-txns-inputs-missingorspent\")})) }";
+        assert!(is_rpc_rejection(electrum));
+        let native = "JsonRpcError { error: Transport(\"Rpc request failed with HTTP status code 500, \
+                      response body: {\\\"result\\\":null, \
+                      \\\"error\\\":{\\\"code\\\":-26,\\\"message\\\":\\\"64: non-final\\\"}}\") }";
+        assert!(is_rpc_rejection(native));
+
+        assert!(!is_rpc_rejection(TIMED_OUT));
+        let unavailable = "JsonRpcError { error: Transport(\"Rpc request failed with HTTP status code 503, \
+                           response body: Work queue depth exceeded\") }";
+        assert!(!is_rpc_rejection(unavailable));
+        assert!(!is_rpc_rejection("Connection refused"));
+    }
+
+    #[test]
+    fn test_tx_not_found() {
+        assert!(is_tx_not_found(TX_NOT_FOUND));
+        let native = "JsonRpcError { error: Transport(\"Rpc request failed with HTTP status code 500, response body: \
+                      {\\\"result\\\":null,\\\"error\\\":{\\\"code\\\":-5,\\\"message\\\":\\\"\
+                      No information available about transaction\\\"}}\") }";
+        assert!(is_tx_not_found(native));
+        assert!(!is_tx_not_found(TIMED_OUT));
+        // the message of a failed request is not the answer of the server
+        assert!(!is_tx_not_found(
+            "Connection refused, no such mempool or blockchain transaction"
+        ));
+    }
+
+    #[test]
+    fn test_native_pubkey_unspents() {
+        let public = Public::from_slice(&[2; 33]).unwrap();
+        let p2pk = SpendKind::P2pk.script(&public);
+        let p2pkh = SpendKind::P2pkh.script(&public);
+        let entry = |txid: u8, script: &Script, amount: &str, confirmations: u64| {
+            json::json!({
+                "txid": H256Json::from([txid; 32]),
+                "vout": 1,
+                "address": "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh",
+                "account": "",
+                "scriptPubKey": hex::encode(script.to_vec()),
+                "amount": json::from_str::<json::Value>(amount).unwrap(),
+                "confirmations": confirmations,
+                "spendable": true
+            })
+        };
+        let unspents: Vec<NativeUnspent> = json::from_value(json::json!([
+            entry(1, &p2pk, "1.5", 10),
+            entry(2, &p2pk, "0.0001", 0),
+            // the address indexes both scripts of the pubkey
+            entry(3, &p2pkh, "2", 5),
+        ]))
+        .unwrap();
+
+        let unspents = native_pubkey_unspents(unspents, SpendKind::P2pk, &p2pk, 8, 1000).unwrap();
+        assert_eq!(unspents.len(), 2);
+        let confirmed = &unspents[0];
+        assert_eq!(confirmed.tx_hash, H256Json::from([1; 32]));
+        assert_eq!(confirmed.tx_pos, 1);
+        assert_eq!(confirmed.value, 150_000_000);
+        assert_eq!(confirmed.height, Some(991));
+        assert_eq!(confirmed.kind, SpendKind::P2pk);
+        assert!(!confirmed.in_mempool());
+        let mempool = &unspents[1];
+        assert_eq!(mempool.value, 10_000);
+        assert_eq!(mempool.height, None);
+        assert!(mempool.in_mempool());
+    }
+
+    #[test]
+    fn test_address_of_another_network_is_rejected() {
+        let kmd_address = "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh";
+        let btc_address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+        // the pubtype and taddr of KMD and BTC
+        let address = address_with_prefixes("KMD", kmd_address, 60, 0).unwrap();
+        assert_eq!(address.to_string(), kmd_address);
+        address_with_prefixes("BTC", btc_address, 0, 0).unwrap();
+
+        let error = address_with_prefixes("KMD", btc_address, 60, 0).unwrap_err();
+        assert!(error.contains("doesn't belong to the KMD network"), "{}", error);
+        assert!(address_with_prefixes("BTC", kmd_address, 0, 0).is_err());
+        assert!(address_with_prefixes("KMD", "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLx", 60, 0).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use clap::{Arg, ArgMatches};
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use common::mm_ctx::{MmArc, MmCtxBuilder};
+use common::mm_error::prelude::*;
+use common::serde_derive::Serialize;
+use common::serde_json as json;
+use keys::Public;
+use notary_tools_rust::cli::CliArgs;
+use notary_tools_rust::maturity::Maturity;
+use notary_tools_rust::merger_conf::{CoinConf, MergerConfig};
+use notary_tools_rust::notary_rpc::{pubkey_address, NotaryRpcOps, SpendKind};
+use notary_tools_rust::rpc_failover::{activate_with_failover, FailoverRpc};
+use notary_tools_rust::MainError;
+use tracing::level_filters::LevelFilter;
+
+const DEFAULT_CONF_PATH: &str = "./merger.json";
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .possible_values(&["table", "json"])
+        .default_value("table")
+        .help("Output format")
+}
+
+/// The balances and the unspents of a single pubkey, the amounts are in satoshis.
+#[derive(Default, Serialize)]
+struct PubkeyStatus {
+    pubkey: String,
+    address: String,
+    /// The value of the P2PK and P2PKH unspents included in the blocks.
+    confirmed: u64,
+    /// The value of the P2PK and P2PKH unspents in the mempool.
+    unconfirmed: u64,
+    /// The number of the P2PK and P2PKH unspents in the mempool.
+    mempool_unspents: usize,
+    /// P2PK unspents below the `output_threshold`, used by the notarizations.
+    notarization_sized: usize,
+    /// P2PK unspents the merger would spend.
+    mature_mergeable: usize,
+    /// P2PK unspents at or above the `output_threshold` waiting for the maturity.
+    immature: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CoinReport {
+    ticker: String,
+    decimals: u8,
+    block_height: Option<u64>,
+    pubkeys: Vec<PubkeyStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn pubkey_status(
+    coin: &UtxoStandardCoin,
+    rpc_client: &FailoverRpc,
+    coin_conf: &CoinConf,
+    maturity: &Maturity,
+    public: &Public,
+    current_block: u64,
+) -> Result<PubkeyStatus, String> {
+    let address = pubkey_address(coin, public)?;
+    let mut status = PubkeyStatus {
+        pubkey: public.to_string(),
+        address: address.to_string(),
+        ..PubkeyStatus::default()
+    };
+    for kind in SpendKind::ALL.iter().copied() {
+        let unspents = rpc_client.pubkey_unspents(kind, public, &address, coin.as_ref().decimals, current_block)?;
+        for unspent in unspents {
+            if unspent.in_mempool() {
+                status.unconfirmed += unspent.value;
+                status.mempool_unspents += 1;
+            } else {
+                status.confirmed += unspent.value;
+            }
+            if kind != SpendKind::P2pk {
+                continue;
+            }
+            if unspent.value < coin_conf.output_threshold {
+                status.notarization_sized += 1;
+            } else if maturity.is_mature(rpc_client, &unspent, current_block)? {
+                status.mature_mergeable += 1;
+            } else {
+                status.immature += 1;
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// Activates the coin and reports every pubkey, the errors are reported instead of failing the whole run.
+fn coin_report<K>(ctx: &MmArc, coin_conf: &CoinConf, keys: &[K], public: impl Fn(&K) -> &Public) -> CoinReport {
+    let mut report = CoinReport {
+        ticker: coin_conf.ticker.clone(),
+        decimals: 0,
+        block_height: None,
+        pubkeys: Vec::new(),
+        error: None,
+    };
+    let (coin, rpc_client) = match activate_with_failover(
+        ctx,
+        &coin_conf.ticker,
+        &coin_conf.mm_conf,
+        &coin_conf.activation_command,
+        coin_conf.retry.clone(),
+    ) {
+        Ok(c) => c,
+        Err(e) => {
+            report.error = Some(format!("Failed to activate: {}", e));
+            return report;
+        },
+    };
+    report.decimals = coin.as_ref().decimals;
+    let current_block = match rpc_client.block_count() {
+        Ok(b) => b,
+        Err(e) => {
+            report.error = Some(format!("Failed to get the block number: {}", e));
+            return report;
+        },
+    };
+    report.block_height = Some(current_block);
+
+    let maturity = Maturity::new(coin_conf.maturity.clone());
+    for key in keys.iter() {
+        let public = public(key);
+        let status = match pubkey_status(&coin, &rpc_client, coin_conf, &maturity, public, current_block) {
+            Ok(s) => s,
+            Err(e) => PubkeyStatus {
+                pubkey: public.to_string(),
+                error: Some(e),
+                ..PubkeyStatus::default()
+            },
+        };
+        report.pubkeys.push(status);
+    }
+    report
+}
+
+/// Formats the satoshis as a decimal amount of the coin.
+fn format_amount(satoshis: u64, decimals: u8) -> String {
+    if decimals == 0 {
+        return satoshis.to_string();
+    }
+    let unit = 10u64.pow(decimals as u32);
+    format!(
+        "{}.{:0width$}",
+        satoshis / unit,
+        satoshis % unit,
+        width = decimals as usize
+    )
+}
+
+fn print_table(reports: &[CoinReport]) {
+    println!(
+        "{:<8} {:>10} {:<66} {:>20} {:>20} {:>8} {:>12} {:>9} {:>9}",
+        "COIN", "HEIGHT", "PUBKEY", "CONFIRMED", "UNCONFIRMED", "MEMPOOL", "NOTARIZATION", "MATURE", "IMMATURE"
+    );
+    for report in reports {
+        let height = report.block_height.map_or_else(|| "-".to_owned(), |h| h.to_string());
+        if let Some(e) = &report.error {
+            println!("{:<8} {:>10} {}", report.ticker, height, e);
+            continue;
+        }
+        for status in report.pubkeys.iter() {
+            match &status.error {
+                Some(e) => println!("{:<8} {:>10} {:<66} {}", report.ticker, height, status.pubkey, e),
+                None => println!(
+                    "{:<8} {:>10} {:<66} {:>20} {:>20} {:>8} {:>12} {:>9} {:>9}",
+                    report.ticker,
+                    height,
+                    status.pubkey,
+                    format_amount(status.confirmed, report.decimals),
+                    format_amount(status.unconfirmed, report.decimals),
+                    status.mempool_unspents,
+                    status.notarization_sized,
+                    status.mature_mergeable,
+                    status.immature
+                ),
+            }
+        }
+    }
+}
+
+fn run(args: &CliArgs, matches: &ArgMatches) -> Result<(), MainError> {
+    let mut conf = MergerConfig::load(&args.conf_path)?;
+    conf.retain_coins(&args.selected_coins())?;
+
+    let ctx = MmCtxBuilder::default().into_mm_arc();
+    // the configured pubkeys don't need the keystore passphrase
+    let reports: Vec<_> = if conf.pubkeys.is_empty() {
+        let keypairs = conf.key_pairs(args.passphrase_stdin)?;
+        conf.coins
+            .iter()
+            .map(|coin| coin_report(&ctx, coin, &keypairs, |keypair| keypair.public()))
+            .collect()
+    } else {
+        let pubkeys = conf.parse_pubkeys()?;
+        conf.coins
+            .iter()
+            .map(|coin| coin_report(&ctx, coin, &pubkeys, |pubkey| pubkey))
+            .collect()
+    };
+
+    match matches.value_of("format") {
+        Some("json") => println!("{}", json::to_string_pretty(&reports).map_err(|e| e.to_string())?),
+        _ => print_table(&reports),
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), MmError<MainError>> {
+    let (mut args, matches) = CliArgs::parse_with(
+        "notary_status",
+        "Prints the balances and the unspents of the notary keys for the coins of the utxo_merger config",
+        DEFAULT_CONF_PATH,
+        false,
+        vec![format_arg()],
+    )?;
+    // the report is printed to stdout, so only the warnings are logged there unless the level is given
+    if matches.occurrences_of("log-level") == 0 {
+        args.log_level = LevelFilter::WARN;
+    }
+    let _log_guard = args.init_logging()?;
+    run(&args, &matches)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(12345, 0), "12345");
+        assert_eq!(format_amount(0, 0), "0");
+        assert_eq!(format_amount(123_456_789, 8), "1.23456789");
+        assert_eq!(format_amount(100_000_000, 8), "1.00000000");
+        assert_eq!(format_amount(1, 8), "0.00000001");
+        assert_eq!(format_amount(99_999_999, 8), "0.99999999");
+        assert_eq!(format_amount(0, 8), "0.00000000");
+    }
+}
@@ -0,0 +1,396 @@
+use crate::fee::FeeSettings;
+use crate::notary_keys::NotaryKeyPair;
+use crate::notary_rpc::{NotaryUnspent, SpendKind};
+use crate::sent_txs::SpentOutpoint;
+use crate::tx_builder::{sign_unsigned_tx, PlannedTx};
+use crate::write_atomically;
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use coins::utxo::{Address, UtxoTx};
+use common::serde_derive::{Deserialize, Serialize};
+use common::serde_json as json;
+use keys::Public;
+use rpc::v1::types::H256 as H256Json;
+use script::{Builder, SignatureVersion, TransactionInputSigner};
+use serde::de::DeserializeOwned;
+use serialization::{deserialize, serialize};
+
+/// `SignatureVersion` of the coin as written to the plan file.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanSignatureVersion {
+    Base,
+    WitnessV0,
+    ForkId,
+}
+
+impl From<SignatureVersion> for PlanSignatureVersion {
+    fn from(version: SignatureVersion) -> PlanSignatureVersion {
+        match version {
+            SignatureVersion::Base => PlanSignatureVersion::Base,
+            SignatureVersion::WitnessV0 => PlanSignatureVersion::WitnessV0,
+            SignatureVersion::ForkId => PlanSignatureVersion::ForkId,
+        }
+    }
+}
+
+impl From<PlanSignatureVersion> for SignatureVersion {
+    fn from(version: PlanSignatureVersion) -> SignatureVersion {
+        match version {
+            PlanSignatureVersion::Base => SignatureVersion::Base,
+            PlanSignatureVersion::WitnessV0 => SignatureVersion::WitnessV0,
+            PlanSignatureVersion::ForkId => SignatureVersion::ForkId,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlanInput {
+    pub tx_hash: H256Json,
+    pub tx_pos: u32,
+    pub value: u64,
+    pub height: Option<u64>,
+    /// The notary public key the output is locked by.
+    pub pubkey: String,
+    #[serde(default)]
+    pub kind: SpendKind,
+    /// Hex encoded transaction the output belongs to. The sighash of the legacy coins doesn't commit
+    /// to the input amounts, so the `value` is checked against it before the fee of the plan is trusted.
+    pub prev_tx: String,
+}
+
+impl PlanInput {
+    pub fn new(unspent: &NotaryUnspent, public: &Public, prev_tx: &[u8]) -> PlanInput {
+        PlanInput {
+            tx_hash: unspent.tx_hash.clone(),
+            tx_pos: unspent.tx_pos,
+            value: unspent.value,
+            height: unspent.height,
+            pubkey: public.to_string(),
+            kind: unspent.kind,
+            prev_tx: hex::encode(prev_tx),
+        }
+    }
+
+    /// Fails unless `prev_tx` has the `tx_hash` and its output `tx_pos` is worth the `value`.
+    fn check_value(&self) -> Result<(), String> {
+        let bytes =
+            hex::decode(&self.prev_tx).map_err(|e| format!("Invalid prev_tx hex of {:?}: {}", self.tx_hash, e))?;
+        let prev_tx: UtxoTx =
+            deserialize(bytes.as_slice()).map_err(|e| format!("Invalid prev_tx of {:?}: {:?}", self.tx_hash, e))?;
+        if H256Json::from(prev_tx.hash().reversed()) != self.tx_hash {
+            return Err(format!("prev_tx doesn't match the transaction {:?}", self.tx_hash));
+        }
+        let output = prev_tx
+            .outputs
+            .get(self.tx_pos as usize)
+            .ok_or_else(|| format!("Transaction {:?} has no output {}", self.tx_hash, self.tx_pos))?;
+        if output.value != self.value {
+            return Err(format!(
+                "Input {:?}:{} is worth {} while {} is planned",
+                self.tx_hash, self.tx_pos, output.value, self.value
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The unsigned merge transaction written by `utxo_merger plan` on the online host.
+/// Contains everything `utxo_merger sign` needs to sign it without the coin RPC.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlannedMerge {
+    pub ticker: String,
+    /// The index of the merge transaction among the ones planned for the coin, starting from 1.
+    pub batch: usize,
+    /// Hex encoded transaction with empty scriptSigs.
+    pub unsigned_tx: String,
+    pub consensus_branch_id: u32,
+    pub signature_version: PlanSignatureVersion,
+    pub fork_id: u32,
+    /// The `wiftype` of the coin, WIF keys are checked against it before signing.
+    pub wif_prefix: u8,
+    pub inputs: Vec<PlanInput>,
+    pub output_address: String,
+    pub output_amount: u64,
+    pub fee: u64,
+    /// The upper bound of the signed transaction size.
+    pub tx_size: usize,
+}
+
+/// The signed merge transaction written by `utxo_merger sign` to be sent by `utxo_merger broadcast`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SignedMerge {
+    pub ticker: String,
+    pub batch: usize,
+    pub tx_hash: H256Json,
+    pub tx_hex: String,
+    pub inputs: Vec<SpentOutpoint>,
+    /// The output value of the signed transaction.
+    #[serde(default)]
+    pub output_amount: u64,
+    /// The fee of the signed transaction, the input values minus the output value.
+    #[serde(default)]
+    pub fee: u64,
+}
+
+impl PlannedMerge {
+    pub fn new(
+        coin: &UtxoStandardCoin,
+        ticker: &str,
+        batch: usize,
+        inputs: Vec<PlanInput>,
+        planned: PlannedTx,
+        output_address: String,
+        wif_prefix: u8,
+    ) -> PlannedMerge {
+        let conf = &coin.as_ref().conf;
+        let output_amount = planned.unsigned.outputs.iter().map(|output| output.value).sum();
+        let consensus_branch_id = planned.unsigned.consensus_branch_id;
+        let unsigned_tx: UtxoTx = planned.unsigned.into();
+        PlannedMerge {
+            ticker: ticker.to_owned(),
+            batch,
+            unsigned_tx: hex::encode(serialize(&unsigned_tx).take()),
+            consensus_branch_id,
+            signature_version: conf.signature_version.into(),
+            fork_id: conf.fork_id,
+            wif_prefix,
+            inputs,
+            output_address,
+            output_amount,
+            fee: planned.fee_amount,
+            tx_size: planned.tx_size,
+        }
+    }
+
+    /// Signs the planned transaction with the `keypairs` the inputs are locked by.
+    /// Fails unless the transaction pays to the `to_address` only and its fee, calculated from the input values
+    /// found in the previous transactions, is within the `fee` settings of the signing host,
+    /// so a tampered plan is not signed.
+    pub fn sign(
+        &self,
+        keypairs: &[NotaryKeyPair],
+        to_address: &Address,
+        fee_settings: &FeeSettings,
+    ) -> Result<SignedMerge, String> {
+        let bytes = hex::decode(&self.unsigned_tx).map_err(|e| format!("Invalid unsigned_tx hex: {}", e))?;
+        let tx: UtxoTx = deserialize(bytes.as_slice()).map_err(|e| format!("Invalid unsigned_tx: {:?}", e))?;
+        let mut unsigned: TransactionInputSigner = tx.into();
+        if unsigned.inputs.len() != self.inputs.len() {
+            return Err(format!(
+                "unsigned_tx has {} inputs while {} are planned",
+                unsigned.inputs.len(),
+                self.inputs.len()
+            ));
+        }
+        let script_pubkey = Builder::build_p2pkh(&to_address.hash).to_bytes();
+        if unsigned.outputs.len() != 1 || unsigned.outputs[0].script_pubkey != script_pubkey {
+            return Err(format!("The transaction doesn't pay to {} only", to_address));
+        }
+        let output_amount = unsigned.outputs[0].value;
+        for input in self.inputs.iter() {
+            input.check_value()?;
+        }
+        let input_value = self
+            .inputs
+            .iter()
+            .try_fold(0u64, |sum, input| sum.checked_add(input.value))
+            .ok_or("The planned input values overflow")?;
+        let fee = input_value.checked_sub(output_amount).ok_or_else(|| {
+            format!(
+                "The transaction pays {} while its inputs are worth {}",
+                output_amount, input_value
+            )
+        })?;
+        if output_amount != self.output_amount || fee != self.fee {
+            return Err(format!(
+                "The transaction pays {} with the fee {} while {} with the fee {} are planned",
+                output_amount, fee, self.output_amount, self.fee
+            ));
+        }
+        unsigned.consensus_branch_id = self.consensus_branch_id;
+
+        let mut input_keypairs = Vec::with_capacity(self.inputs.len());
+        for (input, planned) in unsigned.inputs.iter_mut().zip(self.inputs.iter()) {
+            let outpoint_hash: H256Json = input.previous_output.hash.reversed().into();
+            if outpoint_hash != planned.tx_hash || input.previous_output.index != planned.tx_pos {
+                return Err(format!(
+                    "Input {:?}:{} doesn't match the unsigned_tx",
+                    planned.tx_hash, planned.tx_pos
+                ));
+            }
+            // the amounts are not serialized, but the sighash of some coins commits to them
+            input.amount = planned.value;
+            let keypair = keypairs
+                .iter()
+                .find(|keypair| keypair.public().to_string() == planned.pubkey)
+                .ok_or_else(|| format!("No key of {} is loaded", planned.pubkey))?;
+            input_keypairs.push((planned.kind, &**keypair));
+        }
+
+        let mut max_signed: UtxoTx = unsigned.clone().into();
+        for (input, (kind, keypair)) in max_signed.inputs.iter_mut().zip(input_keypairs.iter()) {
+            input.script_sig = vec![0; kind.max_script_sig_size(keypair.public())].into();
+        }
+        let fee_limit = fee_settings
+            .fee_limit(serialize(&max_signed).len())
+            .ok_or("max_fee must be set to sign the merges of a coin with the estimate fee_policy")?;
+        if fee > fee_limit {
+            return Err(format!("The fee {} exceeds the limit {}", fee, fee_limit));
+        }
+
+        let signed = sign_unsigned_tx(unsigned, &input_keypairs, self.signature_version.into(), self.fork_id)?;
+        Ok(SignedMerge {
+            ticker: self.ticker.clone(),
+            batch: self.batch,
+            tx_hash: signed.hash().reversed().into(),
+            tx_hex: hex::encode(serialize(&signed).take()),
+            inputs: self
+                .inputs
+                .iter()
+                .map(|input| SpentOutpoint {
+                    tx_hash: input.tx_hash.clone(),
+                    tx_pos: input.tx_pos,
+                })
+                .collect(),
+            output_amount,
+            fee,
+        })
+    }
+}
+
+pub fn read_json_file<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Error {} on reading {}", e, path))?;
+    json::from_str(&content).map_err(|e| format!("Error {} on parsing {}", e, path))
+}
+
+pub fn write_json_file<T: serde::Serialize>(path: &str, value: &T) -> Result<(), String> {
+    let content = json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    write_atomically(path, content.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fee::FeePolicy;
+    use chain::{OutPoint, TransactionInput, TransactionOutput};
+    use common::privkey::key_pair_from_seed;
+
+    fn planned_merge(
+        keypair: &NotaryKeyPair,
+        to_address: &Address,
+        input_value: u64,
+        output_amount: u64,
+    ) -> PlannedMerge {
+        let prev_tx = UtxoTx {
+            version: 1,
+            outputs: vec![TransactionOutput {
+                value: input_value,
+                script_pubkey: SpendKind::P2pk.script(keypair.public()).to_bytes(),
+            }],
+            ..UtxoTx::default()
+        };
+        let input = TransactionInput {
+            previous_output: OutPoint {
+                hash: prev_tx.hash(),
+                index: 0,
+            },
+            script_sig: Default::default(),
+            sequence: 0xffff_ffff,
+            script_witness: Vec::new(),
+        };
+        let tx_hash = input.previous_output.hash.reversed().into();
+        let output = TransactionOutput {
+            value: output_amount,
+            script_pubkey: Builder::build_p2pkh(&to_address.hash).to_bytes(),
+        };
+        let tx = UtxoTx {
+            version: 1,
+            inputs: vec![input],
+            outputs: vec![output],
+            ..UtxoTx::default()
+        };
+        PlannedMerge {
+            ticker: "KMD".into(),
+            batch: 1,
+            unsigned_tx: hex::encode(serialize(&tx).take()),
+            consensus_branch_id: 0,
+            signature_version: PlanSignatureVersion::Base,
+            fork_id: 0,
+            wif_prefix: 188,
+            inputs: vec![PlanInput {
+                tx_hash,
+                tx_pos: 0,
+                value: input_value,
+                height: Some(1),
+                pubkey: keypair.public().to_string(),
+                kind: SpendKind::P2pk,
+                prev_tx: hex::encode(serialize(&prev_tx).take()),
+            }],
+            output_address: to_address.to_string(),
+            output_amount,
+            fee: input_value.saturating_sub(output_amount),
+            tx_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_sign_rejects_inflated_fee() {
+        let keypair = NotaryKeyPair::new(key_pair_from_seed("test1 komodo dpow notary nodes").unwrap());
+        let to_address: Address = "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh".parse().unwrap();
+        let fee_settings = FeeSettings::new(FeePolicy::Fixed { amount: 1000 }, None);
+
+        let inflated = planned_merge(&keypair, &to_address, 100_000_000, 10_000_000);
+        let error = inflated.sign(&[keypair], &to_address, &fee_settings).unwrap_err();
+        assert!(error.contains("exceeds the limit 1000"), "{}", error);
+    }
+
+    #[test]
+    fn test_sign_rejects_mismatching_plan() {
+        let keypair = NotaryKeyPair::new(key_pair_from_seed("test1 komodo dpow notary nodes").unwrap());
+        let to_address: Address = "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh".parse().unwrap();
+        let fee_settings = FeeSettings::new(FeePolicy::Fixed { amount: 1000 }, Some(1000));
+        let keypairs = [keypair];
+
+        // the plan understates the fee of the transaction
+        let mut understated = planned_merge(&keypairs[0], &to_address, 100_000_000, 10_000_000);
+        understated.output_amount = 99_999_000;
+        understated.fee = 1000;
+        assert!(understated.sign(&keypairs, &to_address, &fee_settings).is_err());
+
+        // the output is worth more than the inputs
+        let underflow = planned_merge(&keypairs[0], &to_address, 1000, 2000);
+        let error = underflow.sign(&keypairs, &to_address, &fee_settings).unwrap_err();
+        assert!(error.contains("while its inputs are worth 1000"), "{}", error);
+    }
+
+    #[test]
+    fn test_sign_checks_input_values() {
+        let keypair = NotaryKeyPair::new(key_pair_from_seed("test1 komodo dpow notary nodes").unwrap());
+        let to_address: Address = "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh".parse().unwrap();
+        let fee_settings = FeeSettings::new(FeePolicy::Fixed { amount: 1000 }, None);
+        let keypairs = [keypair];
+
+        // the plan understates the input value to hide the fee, the legacy sighash wouldn't catch it
+        let mut understated = planned_merge(&keypairs[0], &to_address, 100_000_000, 10_000_000);
+        understated.inputs[0].value = 10_001_000;
+        understated.fee = 1000;
+        let error = understated.sign(&keypairs, &to_address, &fee_settings).unwrap_err();
+        assert!(
+            error.contains("is worth 100000000 while 10001000 is planned"),
+            "{}",
+            error
+        );
+
+        // the previous transaction is replaced along with the value
+        let other = planned_merge(&keypairs[0], &to_address, 10_001_000, 0);
+        let mut replaced = planned_merge(&keypairs[0], &to_address, 100_000_000, 99_999_000);
+        replaced.inputs[0].prev_tx = other.inputs[0].prev_tx.clone();
+        replaced.inputs[0].value = 10_001_000;
+        let error = replaced.sign(&keypairs, &to_address, &fee_settings).unwrap_err();
+        assert!(error.contains("prev_tx doesn't match"), "{}", error);
+
+        let valid = planned_merge(&keypairs[0], &to_address, 100_000_000, 99_999_000);
+        let signed = valid.sign(&keypairs, &to_address, &fee_settings).unwrap();
+        assert_eq!(signed.fee, 1000);
+    }
+}
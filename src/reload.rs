@@ -0,0 +1,49 @@
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+
+/// How often the modification time of the config file is checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+fn modified(path: &str) -> Option<SystemTime> { std::fs::metadata(path).and_then(|m| m.modified()).ok() }
+
+/// Requests a config reload through `reloads` whenever the file at `conf_path` is modified
+/// or the process receives SIGHUP. Must be called within the Tokio runtime.
+pub fn spawn_reload_triggers(conf_path: String, reloads: UnboundedSender<()>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangups) => {
+                let reloads = reloads.clone();
+                tokio::spawn(async move {
+                    while hangups.recv().await.is_some() {
+                        info!("Got SIGHUP, reloading the config");
+                        if reloads.send(()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            },
+            Err(e) => warn!(error = %e, "Failed to handle SIGHUP, the config is reloaded on the file changes only"),
+        }
+    }
+
+    tokio::spawn(async move {
+        let mut last_modified = modified(&conf_path);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = modified(&conf_path);
+            if current.is_none() || current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            info!(%conf_path, "The config file is modified, reloading it");
+            if reloads.send(()).is_err() {
+                return;
+            }
+        }
+    });
+}
@@ -0,0 +1,458 @@
+use crate::activate_coin;
+use crate::notary_rpc::{is_rpc_rejection, NotaryRpcOps, NotaryUnspent, SpendKind};
+use crate::sent_txs::SpentOutpoint;
+use coins::utxo::rpc_clients::UtxoRpcClientEnum;
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use coins::utxo::{Address, UtxoTx};
+use common::mm_ctx::MmArc;
+use common::serde_derive::Deserialize;
+use common::serde_json::{self as json, Value as Json};
+use keys::Public;
+use rpc::v1::types::H256 as H256Json;
+use serialization::deserialize;
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 1000;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 10_000;
+const DEFAULT_UNHEALTHY_SECS: u64 = 300;
+
+/// The broadcast errors meaning the server already has the transaction,
+/// e.g. it was accepted by the previous attempt that failed to get the response.
+const ALREADY_KNOWN_ERRORS: [&str; 4] = [
+    "txn-already-known",
+    "txn-already-in-mempool",
+    "already in mempool",
+    "already in block chain",
+];
+
+fn default_max_attempts() -> u32 { DEFAULT_MAX_ATTEMPTS }
+
+fn default_initial_backoff_ms() -> u64 { DEFAULT_INITIAL_BACKOFF_MS }
+
+fn default_max_backoff_ms() -> u64 { DEFAULT_MAX_BACKOFF_MS }
+
+fn default_unhealthy_secs() -> u64 { DEFAULT_UNHEALTHY_SECS }
+
+/// How the RPC calls are retried.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RetryConf {
+    /// The number of attempts of a call including the first one, each attempt goes to the next healthy server.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// The delay before the second attempt, doubled before every next one up to `max_backoff_ms`.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// A failed server is tried again only after this period unless every server has failed.
+    #[serde(default = "default_unhealthy_secs")]
+    pub unhealthy_secs: u64,
+}
+
+impl Default for RetryConf {
+    fn default() -> RetryConf {
+        RetryConf {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff_ms: DEFAULT_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+            unhealthy_secs: DEFAULT_UNHEALTHY_SECS,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct ServerHealth {
+    unhealthy_until: Option<Instant>,
+    /// Counted since the last `log_usage`.
+    calls: u32,
+    failures: u32,
+}
+
+struct FailoverState {
+    /// The server the calls go to while it's healthy.
+    current: usize,
+    servers: Vec<ServerHealth>,
+}
+
+/// Sends the RPC calls to one of the `servers`, switching to the next one once it fails.
+/// The transport failures and timeouts are retried with an exponential backoff, while the errors returned
+/// by the server, e.g. a rejected transaction or a transaction not found, are returned on the first attempt
+/// as every server would return the same, see `is_rpc_rejection`.
+pub struct FailoverRpc<C = UtxoRpcClientEnum> {
+    /// The servers along with their names, e.g. the Electrum URLs.
+    servers: Vec<(String, C)>,
+    retry: RetryConf,
+    state: Mutex<FailoverState>,
+}
+
+impl<C: Clone> FailoverRpc<C> {
+    /// `servers` must not be empty.
+    pub fn new(servers: Vec<(String, C)>, retry: RetryConf) -> FailoverRpc<C> {
+        let state = FailoverState {
+            current: 0,
+            servers: vec![ServerHealth::default(); servers.len()],
+        };
+        FailoverRpc {
+            servers,
+            retry,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// The same servers with the new retry settings, the health of the servers is reset.
+    pub fn with_retry(&self, retry: RetryConf) -> FailoverRpc<C> { FailoverRpc::new(self.servers.clone(), retry) }
+}
+
+impl<C> FailoverRpc<C> {
+    fn state(&self) -> MutexGuard<FailoverState> { self.state.lock().unwrap_or_else(PoisonError::into_inner) }
+
+    /// The current server if it's healthy, otherwise the next healthy one after it.
+    /// The server recovering the soonest is picked if every server is unhealthy.
+    fn pick_server(&self) -> usize {
+        let mut state = self.state();
+        let now = Instant::now();
+        let count = state.servers.len();
+        let healthy = (0..count)
+            .map(|offset| (state.current + offset) % count)
+            .find(|index| state.servers[*index].unhealthy_until.map_or(true, |until| until <= now));
+        let index = healthy.unwrap_or_else(|| {
+            (0..count)
+                .min_by_key(|index| state.servers[*index].unhealthy_until)
+                .unwrap_or(0)
+        });
+        state.current = index;
+        index
+    }
+
+    fn record(&self, index: usize, succeeded: bool) {
+        let mut state = self.state();
+        let count = state.servers.len();
+        let health = &mut state.servers[index];
+        health.calls += 1;
+        if succeeded {
+            health.unhealthy_until = None;
+            return;
+        }
+        health.failures += 1;
+        health.unhealthy_until = Some(Instant::now() + Duration::from_secs(self.retry.unhealthy_secs));
+        if state.current == index {
+            state.current = (index + 1) % count;
+        }
+    }
+
+    fn call<T>(&self, method: &str, f: impl Fn(&C) -> Result<T, String>) -> Result<T, String> {
+        let max_attempts = self.retry.max_attempts.max(1);
+        let mut backoff = Duration::from_millis(self.retry.initial_backoff_ms);
+        let mut last_error = String::new();
+        for attempt in 1..=max_attempts {
+            if attempt > 1 {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_millis(self.retry.max_backoff_ms));
+            }
+            let index = self.pick_server();
+            let (server, client) = &self.servers[index];
+            match f(client) {
+                Ok(result) => {
+                    self.record(index, true);
+                    return Ok(result);
+                },
+                Err(e) if is_rpc_rejection(&e) => {
+                    // the server is reachable, just the call is rejected
+                    self.record(index, true);
+                    return Err(e);
+                },
+                Err(e) => {
+                    warn!(%server, method, attempt, error = %e, "The RPC call failed");
+                    self.record(index, false);
+                    last_error = e;
+                },
+            }
+        }
+        Err(format!(
+            "{} failed {} times, the last error: {}",
+            method, max_attempts, last_error
+        ))
+    }
+
+    /// Logs the calls and failures of every used server since the previous call, e.g. once per merge cycle.
+    pub fn log_usage(&self, ticker: &str) {
+        let mut state = self.state();
+        let now = Instant::now();
+        let usage: Vec<_> = state
+            .servers
+            .iter()
+            .zip(self.servers.iter())
+            .filter(|(health, _)| health.calls > 0)
+            .map(|(health, (server, _))| {
+                let unhealthy = health.unhealthy_until.map_or(false, |until| until > now);
+                format!(
+                    "{} calls={} failures={}{}",
+                    server,
+                    health.calls,
+                    health.failures,
+                    if unhealthy { " unhealthy" } else { "" }
+                )
+            })
+            .collect();
+        for health in state.servers.iter_mut() {
+            health.calls = 0;
+            health.failures = 0;
+        }
+        if !usage.is_empty() {
+            info!(ticker, servers = %usage.join(", "), "RPC servers used");
+        }
+    }
+}
+
+impl<C: NotaryRpcOps> NotaryRpcOps for FailoverRpc<C> {
+    fn block_count(&self) -> Result<u64, String> { self.call("block_count", |client| client.block_count()) }
+
+    fn pubkey_unspents(
+        &self,
+        kind: SpendKind,
+        public: &Public,
+        address: &Address,
+        decimals: u8,
+        current_block: u64,
+    ) -> Result<Vec<NotaryUnspent>, String> {
+        self.call("pubkey_unspents", |client| {
+            client.pubkey_unspents(kind, public, address, decimals, current_block)
+        })
+    }
+
+    /// The transaction already known to the server is sent successfully, so a retry after the lost response
+    /// of the accepted transaction doesn't fail.
+    fn send_raw_tx(&self, tx: &[u8]) -> Result<String, String> {
+        self.call("send_raw_tx", |client| match client.send_raw_tx(tx) {
+            Err(e) if is_already_known(&e) => {
+                info!(error = %e, "The server already has the transaction");
+                tx_hash(tx)
+            },
+            result => result,
+        })
+    }
+
+    fn is_coinbase_tx(&self, tx_hash: &H256Json) -> Result<bool, String> {
+        self.call("is_coinbase_tx", |client| client.is_coinbase_tx(tx_hash))
+    }
+
+    fn tx_bytes(&self, tx_hash: &H256Json) -> Result<Vec<u8>, String> {
+        self.call("tx_bytes", |client| client.tx_bytes(tx_hash))
+    }
+
+    /// The transaction not found once it's dropped from the mempool is an RPC rejection,
+    /// so it doesn't mark the server unhealthy.
+    fn tx_confirmations(&self, tx_hash: &H256Json) -> Result<u32, String> {
+        self.call("tx_confirmations", |client| client.tx_confirmations(tx_hash))
+    }
+
+    fn mempool_spent_outpoints(&self, kind: SpendKind, public: &Public) -> Result<HashSet<SpentOutpoint>, String> {
+        self.call("mempool_spent_outpoints", |client| {
+            client.mempool_spent_outpoints(kind, public)
+        })
+    }
+
+    fn estimate_fee_rate(&self, decimals: u8, n_blocks: u32) -> Result<u64, String> {
+        self.call("estimate_fee_rate", |client| {
+            client.estimate_fee_rate(decimals, n_blocks)
+        })
+    }
+}
+
+fn is_already_known(error: &str) -> bool {
+    let error = error.to_lowercase();
+    ALREADY_KNOWN_ERRORS.iter().any(|known| error.contains(known))
+}
+
+/// The hash of the serialized transaction formatted like the one returned by `send_raw_tx`.
+fn tx_hash(tx: &[u8]) -> Result<String, String> {
+    let tx: UtxoTx = deserialize(tx).map_err(|e| format!("{:?}", e))?;
+    Ok(format!("{:?}", H256Json::from(tx.hash().reversed())))
+}
+
+/// Activates the coin with a client per Electrum server of the `activation_command`, so the calls can be
+/// switched between the servers. Every server is connected once, the coin is the first connected one.
+/// The coin RPC is the only server of the native daemon or a single Electrum server.
+/// The servers failing to connect are skipped until the coin is activated again.
+pub fn activate_with_failover(
+    ctx: &MmArc,
+    ticker: &str,
+    mm_conf: &Json,
+    activation_command: &Json,
+    retry: RetryConf,
+) -> Result<(UtxoStandardCoin, FailoverRpc), String> {
+    let servers = match activation_command["servers"].as_array() {
+        Some(s) if activation_command["method"] == "electrum" && s.len() > 1 => s,
+        _ => {
+            let coin = activate_coin(ctx, ticker, mm_conf, activation_command)?;
+            let name = activation_command["servers"][0]["url"]
+                .as_str()
+                .unwrap_or("native")
+                .to_owned();
+            let servers = vec![(name, coin.as_ref().rpc_client.clone())];
+            return Ok((coin, FailoverRpc::new(servers, retry)));
+        },
+    };
+
+    let mut coin = None;
+    let mut clients = Vec::with_capacity(servers.len());
+    let mut last_error = String::new();
+    for server in servers {
+        let name = server["url"].as_str().unwrap_or_default().to_owned();
+        let mut command = activation_command.clone();
+        command["servers"] = json::json!([server]);
+        match activate_coin(ctx, ticker, mm_conf, &command) {
+            Ok(server_coin) => {
+                clients.push((name, server_coin.as_ref().rpc_client.clone()));
+                coin.get_or_insert(server_coin);
+            },
+            Err(e) => {
+                warn!(%ticker, server = %name, error = %e, "Failed to connect to the server, skipping it");
+                last_error = e;
+            },
+        }
+    }
+    let coin = coin.ok_or_else(|| format!("Failed to connect to any server, the last error: {}", last_error))?;
+    Ok((coin, FailoverRpc::new(clients, retry)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notary_rpc::tests::TX_NOT_FOUND;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// Fails the block number calls until `failures` is exhausted.
+    #[derive(Clone)]
+    struct TestClient {
+        height: u64,
+        failures: Arc<AtomicU32>,
+    }
+
+    impl TestClient {
+        fn new(height: u64, failures: u32) -> TestClient {
+            TestClient {
+                height,
+                failures: Arc::new(AtomicU32::new(failures)),
+            }
+        }
+    }
+
+    impl NotaryRpcOps for TestClient {
+        fn block_count(&self) -> Result<u64, String> {
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return Err("Connection refused".into());
+            }
+            Ok(self.height)
+        }
+
+        fn pubkey_unspents(
+            &self,
+            _kind: SpendKind,
+            _public: &Public,
+            _address: &Address,
+            _decimals: u8,
+            _current_block: u64,
+        ) -> Result<Vec<NotaryUnspent>, String> {
+            Ok(Vec::new())
+        }
+
+        fn send_raw_tx(&self, _tx: &[u8]) -> Result<String, String> {
+            Err("the transaction was rejected by network rules.\n\ntxn-already-known".into())
+        }
+
+        fn is_coinbase_tx(&self, _tx_hash: &H256Json) -> Result<bool, String> { Ok(false) }
+
+        fn tx_bytes(&self, _tx_hash: &H256Json) -> Result<Vec<u8>, String> { Err("Not supported".into()) }
+
+        fn tx_confirmations(&self, _tx_hash: &H256Json) -> Result<u32, String> { Err(TX_NOT_FOUND.into()) }
+
+        fn mempool_spent_outpoints(
+            &self,
+            _kind: SpendKind,
+            _public: &Public,
+        ) -> Result<HashSet<SpentOutpoint>, String> {
+            Ok(HashSet::new())
+        }
+
+        fn estimate_fee_rate(&self, _decimals: u8, _n_blocks: u32) -> Result<u64, String> { Ok(1000) }
+    }
+
+    fn test_retry(max_attempts: u32) -> RetryConf {
+        RetryConf {
+            max_attempts,
+            initial_backoff_ms: 0,
+            max_backoff_ms: 0,
+            unhealthy_secs: 300,
+        }
+    }
+
+    #[test]
+    fn test_fails_over_to_next_server() {
+        let servers = vec![
+            ("first".to_owned(), TestClient::new(100, u32::MAX)),
+            ("second".to_owned(), TestClient::new(200, 0)),
+        ];
+        let rpc = FailoverRpc::new(servers, test_retry(3));
+        assert_eq!(rpc.block_count(), Ok(200));
+        // the unhealthy first server is not tried again
+        assert_eq!(rpc.block_count(), Ok(200));
+        let state = rpc.state();
+        assert_eq!(state.current, 1);
+        assert_eq!(state.servers[0].failures, 1);
+        assert!(state.servers[0].unhealthy_until.is_some());
+        assert_eq!(state.servers[1].calls, 2);
+    }
+
+    #[test]
+    fn test_retries_until_attempts_exhausted() {
+        let rpc = FailoverRpc::new(vec![("single".to_owned(), TestClient::new(100, 2))], test_retry(3));
+        assert_eq!(rpc.block_count(), Ok(100));
+
+        let rpc = FailoverRpc::new(vec![("single".to_owned(), TestClient::new(100, 3))], test_retry(3));
+        assert!(rpc.block_count().unwrap_err().contains("block_count failed 3 times"));
+        assert_eq!(rpc.block_count(), Ok(100));
+    }
+
+    #[test]
+    fn test_rejection_is_not_retried() {
+        let servers = vec![
+            ("first".to_owned(), TestClient::new(100, 0)),
+            ("second".to_owned(), TestClient::new(200, 0)),
+        ];
+        let rpc = FailoverRpc::new(servers, test_retry(3));
+        assert_eq!(rpc.tx_confirmations(&H256Json::default()), Err(TX_NOT_FOUND.to_owned()));
+        let state = rpc.state();
+        assert_eq!(state.current, 0);
+        assert_eq!(state.servers[0].calls, 1);
+        assert_eq!(state.servers[0].failures, 0);
+        assert_eq!(state.servers[1].calls, 0);
+        assert!(state.servers.iter().all(|health| health.unhealthy_until.is_none()));
+    }
+
+    #[test]
+    fn test_already_known_tx_is_sent() {
+        let tx = UtxoTx {
+            version: 1,
+            ..UtxoTx::default()
+        };
+        let tx_bytes = serialization::serialize(&tx).take();
+        let rpc = FailoverRpc::new(vec![("single".to_owned(), TestClient::new(100, 0))], test_retry(3));
+        assert_eq!(
+            rpc.send_raw_tx(&tx_bytes),
+            Ok(format!("{:?}", H256Json::from(tx.hash().reversed())))
+        );
+        assert!(rpc.state().servers[0].unhealthy_until.is_none());
+
+        assert!(is_already_known("Transaction already in block chain"));
+        assert!(!is_already_known("bad-txns-inputs-missingorspent"));
+    }
+}
@@ -0,0 +1,125 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+struct ScheduledCoin {
+    ticker: String,
+    interval: Duration,
+    next_run: Instant,
+}
+
+/// Tracks the next run time of each coin, so the coins can be processed at their own intervals.
+/// A random delay up to `jitter` is added to every run, so the coins don't hit the RPC at the same time.
+pub struct Scheduler {
+    coins: Vec<ScheduledCoin>,
+    jitter: Duration,
+}
+
+impl Scheduler {
+    pub fn new(jitter: Duration) -> Scheduler {
+        Scheduler {
+            coins: Vec::new(),
+            jitter,
+        }
+    }
+
+    pub fn set_jitter(&mut self, jitter: Duration) { self.jitter = jitter; }
+
+    /// Schedules the first run of a new coin within the `jitter` from `now`.
+    /// The next run of a known coin is brought forward if it's later than the new `interval` allows.
+    pub fn insert(&mut self, ticker: &str, interval: Duration, now: Instant) {
+        match self.coins.iter_mut().find(|coin| coin.ticker == ticker) {
+            Some(coin) => {
+                coin.interval = interval;
+                coin.next_run = coin.next_run.min(now + interval);
+            },
+            None => self.coins.push(ScheduledCoin {
+                ticker: ticker.to_owned(),
+                interval,
+                next_run: now + random_jitter(self.jitter),
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, ticker: &str) { self.coins.retain(|coin| coin.ticker != ticker); }
+
+    /// The tickers of the coins due to run at `now`, each of them is rescheduled after its interval.
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        for coin in self.coins.iter_mut() {
+            if coin.next_run <= now {
+                coin.next_run = now + coin.interval + random_jitter(self.jitter);
+                due.push(coin.ticker.clone());
+            }
+        }
+        due
+    }
+
+    /// The earliest next run among the coins, `None` if there are no coins.
+    pub fn next_run(&self) -> Option<Instant> { self.coins.iter().map(|coin| coin.next_run).min() }
+}
+
+fn random_jitter(jitter: Duration) -> Duration {
+    if jitter == Duration::from_secs(0) {
+        return jitter;
+    }
+    let millis = rand::thread_rng().gen_range(0, jitter.as_millis() as u64 + 1);
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coins_run_at_own_intervals() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(Duration::from_secs(0));
+        scheduler.insert("KMD", Duration::from_secs(60), now);
+        scheduler.insert("RICK", Duration::from_secs(300), now);
+        assert_eq!(scheduler.take_due(now), vec!["KMD", "RICK"]);
+        assert!(scheduler.take_due(now).is_empty());
+        assert_eq!(scheduler.next_run(), Some(now + Duration::from_secs(60)));
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(scheduler.take_due(later), vec!["KMD"]);
+        assert_eq!(scheduler.next_run(), Some(now + Duration::from_secs(120)));
+
+        let later = now + Duration::from_secs(300);
+        assert_eq!(scheduler.take_due(later), vec!["KMD", "RICK"]);
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(Duration::from_secs(0));
+        scheduler.insert("KMD", Duration::from_secs(600), now);
+        scheduler.take_due(now);
+        assert_eq!(scheduler.next_run(), Some(now + Duration::from_secs(600)));
+
+        let later = now + Duration::from_secs(10);
+        scheduler.insert("KMD", Duration::from_secs(60), later);
+        assert_eq!(scheduler.next_run(), Some(later + Duration::from_secs(60)));
+
+        scheduler.remove("KMD");
+        assert_eq!(scheduler.next_run(), None);
+    }
+
+    #[test]
+    fn test_jitter_is_bounded() {
+        let now = Instant::now();
+        let jitter = Duration::from_secs(10);
+        let mut scheduler = Scheduler::new(jitter);
+        for i in 0..20 {
+            scheduler.insert(&i.to_string(), Duration::from_secs(60), now);
+        }
+        assert!(scheduler.next_run().unwrap() <= now + jitter);
+
+        let later = now + jitter;
+        assert_eq!(scheduler.take_due(later).len(), 20);
+        assert!(scheduler.next_run().unwrap() >= later + Duration::from_secs(60));
+        assert!(scheduler
+            .coins
+            .iter()
+            .all(|coin| coin.next_run <= later + Duration::from_secs(60) + jitter));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::notary_rpc::{is_tx_not_found, NotaryRpcOps, NotaryUnspent};
+use crate::write_atomically;
+use common::now_ms;
+use common::serde_derive::{Deserialize, Serialize};
+use common::serde_json as json;
+use rpc::v1::types::H256 as H256Json;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tracing::warn;
+
+/// A pending transaction that can't be found by the RPC for this long is considered dropped from the mempool.
+const DROPPED_AFTER_SECS: u64 = 24 * 60 * 60;
+/// Confirmed and dropped transactions are removed from the store after this period.
+const PRUNE_AFTER_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct SpentOutpoint {
+    pub tx_hash: H256Json,
+    pub tx_pos: u32,
+}
+
+impl From<&NotaryUnspent> for SpentOutpoint {
+    fn from(unspent: &NotaryUnspent) -> SpentOutpoint {
+        SpentOutpoint {
+            tx_hash: unspent.tx_hash.clone(),
+            tx_pos: unspent.tx_pos,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SentTxStatus {
+    Pending,
+    Confirmed { height: u64 },
+    Dropped,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SentTx {
+    pub ticker: String,
+    pub tx_hash: H256Json,
+    pub inputs: Vec<SpentOutpoint>,
+    /// UNIX timestamp in seconds.
+    pub sent_at: u64,
+    #[serde(flatten)]
+    pub status: SentTxStatus,
+    /// UNIX timestamp of the last status change in seconds.
+    pub updated_at: u64,
+}
+
+impl SentTx {
+    pub fn pending(ticker: &str, tx_hash: H256Json, inputs: Vec<SpentOutpoint>) -> SentTx {
+        let now = now_ms() / 1000;
+        SentTx {
+            ticker: ticker.to_owned(),
+            tx_hash,
+            inputs,
+            sent_at: now,
+            status: SentTxStatus::Pending,
+            updated_at: now,
+        }
+    }
+}
+
+/// The broadcast transactions persisted in a JSON file, so the inputs of the pending ones are not spent again
+/// after a restart. Shared by the concurrently processed coins, the lock is never held during the RPC calls,
+/// so a hanging coin doesn't block the others.
+pub struct SentTxStore {
+    path: String,
+    txs: Mutex<Vec<SentTx>>,
+}
+
+impl SentTxStore {
+    /// Loads the store from the `path`, a missing file is treated as an empty store.
+    pub fn load(path: &str) -> Result<SentTxStore, String> {
+        let txs = if Path::new(path).exists() {
+            let content = std::fs::read_to_string(path).map_err(|e| format!("Error {} on reading {}", e, path))?;
+            json::from_str(&content).map_err(|e| format!("Error {} on parsing {}", e, path))?
+        } else {
+            Vec::new()
+        };
+        Ok(SentTxStore {
+            path: path.to_owned(),
+            txs: Mutex::new(txs),
+        })
+    }
+
+    /// A panic of another coin task doesn't leave the transactions inconsistent, so the poisoning is ignored.
+    fn txs(&self) -> MutexGuard<Vec<SentTx>> { self.txs.lock().unwrap_or_else(PoisonError::into_inner) }
+
+    fn save(&self, txs: &[SentTx]) -> Result<(), String> {
+        let content = json::to_string_pretty(txs).map_err(|e| e.to_string())?;
+        write_atomically(&self.path, content.as_bytes())
+    }
+
+    pub fn add(&self, tx: SentTx) -> Result<(), String> {
+        let mut txs = self.txs();
+        txs.push(tx);
+        self.save(&txs)
+    }
+
+    /// The inputs of the pending `ticker` transactions that must not be selected again.
+    pub fn pending_inputs(&self, ticker: &str) -> HashSet<SpentOutpoint> {
+        self.txs()
+            .iter()
+            .filter(|tx| tx.ticker == ticker && tx.status == SentTxStatus::Pending)
+            .flat_map(|tx| tx.inputs.iter().cloned())
+            .collect()
+    }
+
+    /// The hashes of the pending `ticker` transactions.
+    pub fn pending_txs(&self, ticker: &str) -> Vec<H256Json> {
+        self.txs()
+            .iter()
+            .filter(|tx| tx.ticker == ticker && tx.status == SentTxStatus::Pending)
+            .map(|tx| tx.tx_hash.clone())
+            .collect()
+    }
+
+    /// Updates the statuses of the pending `ticker` transactions and prunes the settled ones.
+    pub fn reconcile(&self, ticker: &str, rpc_client: &impl NotaryRpcOps, current_block: u64) -> Result<(), String> {
+        let now = now_ms() / 1000;
+        let pending: Vec<(H256Json, u64)> = self
+            .txs()
+            .iter()
+            .filter(|tx| tx.ticker == ticker && tx.status == SentTxStatus::Pending)
+            .map(|tx| (tx.tx_hash.clone(), tx.sent_at))
+            .collect();
+
+        let mut updates = Vec::new();
+        for (tx_hash, sent_at) in pending {
+            let status = match rpc_client.tx_confirmations(&tx_hash) {
+                Ok(0) => continue,
+                Ok(confirmations) => {
+                    let height = (current_block + 1).saturating_sub(confirmations as u64);
+                    SentTxStatus::Confirmed { height }
+                },
+                // a lookup failing for another reason, e.g. the server is unreachable, is retried on the next cycle
+                Err(e) if is_tx_not_found(&e) && now.saturating_sub(sent_at) > DROPPED_AFTER_SECS => {
+                    warn!(error = %e, ?tx_hash, "Sent transaction is not found, considering it dropped");
+                    SentTxStatus::Dropped
+                },
+                Err(e) => {
+                    warn!(error = %e, ?tx_hash, "Failed to get the sent transaction confirmations");
+                    continue;
+                },
+            };
+            updates.push((tx_hash, status));
+        }
+
+        let mut txs = self.txs();
+        let mut changed = false;
+        for (tx_hash, status) in updates {
+            let tx = txs
+                .iter_mut()
+                .find(|tx| tx.ticker == ticker && tx.tx_hash == tx_hash && tx.status == SentTxStatus::Pending);
+            if let Some(tx) = tx {
+                tx.status = status;
+                tx.updated_at = now;
+                changed = true;
+            }
+        }
+
+        let count = txs.len();
+        txs.retain(|tx| tx.status == SentTxStatus::Pending || now.saturating_sub(tx.updated_at) < PRUNE_AFTER_SECS);
+        if changed || txs.len() != count {
+            self.save(&txs)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notary_rpc::tests::TestRpc;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("notary_{}_{}.json", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn outpoint(hash: u8, tx_pos: u32) -> SpentOutpoint {
+        SpentOutpoint {
+            tx_hash: [hash; 32].into(),
+            tx_pos,
+        }
+    }
+
+    #[test]
+    fn test_pending_inputs_survive_reload() {
+        let path = temp_path("sent_txs_reload");
+        let store = SentTxStore::load(&path).unwrap();
+        assert!(store.pending_inputs("KMD").is_empty());
+
+        let inputs = vec![outpoint(1, 0), outpoint(1, 1)];
+        store.add(SentTx::pending("KMD", [10; 32].into(), inputs)).unwrap();
+        store
+            .add(SentTx::pending("RICK", [11; 32].into(), vec![outpoint(2, 0)]))
+            .unwrap();
+
+        let reloaded = SentTxStore::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pending = reloaded.pending_inputs("KMD");
+        assert_eq!(pending.len(), 2);
+        assert!(pending.contains(&outpoint(1, 1)));
+        assert!(!pending.contains(&outpoint(2, 0)));
+        assert_eq!(reloaded.pending_txs("RICK"), vec![H256Json::from([11; 32])]);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let path = temp_path("sent_txs_reconcile");
+        let store = SentTxStore::load(&path).unwrap();
+        let confirmed: H256Json = [1; 32].into();
+        let in_mempool: H256Json = [2; 32].into();
+        let dropped: H256Json = [3; 32].into();
+        let not_found_yet: H256Json = [4; 32].into();
+        store
+            .add(SentTx::pending("KMD", confirmed.clone(), vec![outpoint(10, 0)]))
+            .unwrap();
+        store
+            .add(SentTx::pending("KMD", in_mempool.clone(), vec![outpoint(11, 0)]))
+            .unwrap();
+        let mut old_tx = SentTx::pending("KMD", dropped.clone(), vec![outpoint(12, 0)]);
+        old_tx.sent_at -= DROPPED_AFTER_SECS + 1;
+        store.add(old_tx).unwrap();
+        store
+            .add(SentTx::pending("KMD", not_found_yet.clone(), vec![outpoint(13, 0)]))
+            .unwrap();
+
+        let rpc = TestRpc {
+            confirmations: vec![(confirmed.clone(), 3), (in_mempool.clone(), 0)]
+                .into_iter()
+                .collect(),
+            ..TestRpc::default()
+        };
+        store.reconcile("KMD", &rpc, 1000).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let status = |tx_hash: &H256Json| {
+            let txs = store.txs();
+            txs.iter().find(|tx| &tx.tx_hash == tx_hash).unwrap().status.clone()
+        };
+        assert_eq!(status(&confirmed), SentTxStatus::Confirmed { height: 998 });
+        assert_eq!(status(&in_mempool), SentTxStatus::Pending);
+        assert_eq!(status(&dropped), SentTxStatus::Dropped);
+        assert_eq!(status(&not_found_yet), SentTxStatus::Pending);
+        assert_eq!(store.pending_txs("KMD"), vec![in_mempool, not_found_yet]);
+        assert_eq!(store.pending_inputs("KMD").len(), 2);
+    }
+
+    #[test]
+    fn test_reconcile_keeps_unreachable_tx_pending() {
+        let path = temp_path("sent_txs_unreachable");
+        let store = SentTxStore::load(&path).unwrap();
+        let unreachable: H256Json = [1; 32].into();
+        let mut old_tx = SentTx::pending("KMD", unreachable.clone(), vec![outpoint(10, 0)]);
+        old_tx.sent_at -= DROPPED_AFTER_SECS + 1;
+        store.add(old_tx).unwrap();
+
+        let rpc = TestRpc {
+            unreachable_txs: vec![unreachable.clone()].into_iter().collect(),
+            ..TestRpc::default()
+        };
+        store.reconcile("KMD", &rpc, 1000).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(store.pending_txs("KMD"), vec![unreachable]);
+        assert_eq!(store.pending_inputs("KMD").len(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::fee::{FeeSettings, TxFee};
+use crate::notary_rpc::{NotaryUnspent, SpendKind};
+use chain::TransactionOutput;
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use coins::utxo::{p2pk_spend, p2pkh_spend, UtxoTx};
+use keys::{KeyPair, Public};
+use rpc::v1::types::H256 as H256Json;
+use script::{SignatureVersion, TransactionInputSigner};
+use serialization::serialize;
+
+/// DER signatures have variable length, so the final signature of each input may turn out 1 byte longer
+/// than the one produced while estimating the transaction size.
+const SIGNATURE_SIZE_MARGIN: usize = 1;
+
+pub struct SignedTx {
+    pub tx_hash: H256Json,
+    pub tx_bytes: Vec<u8>,
+    pub fee_amount: u64,
+}
+
+/// The unsigned transaction built by the online host to be signed offline.
+pub struct PlannedTx {
+    pub unsigned: TransactionInputSigner,
+    /// The size the transaction would have with the largest possible signatures.
+    pub tx_size: usize,
+    pub fee_amount: u64,
+}
+
+/// The unsigned transaction spending `unspents` to the `outputs`.
+pub fn unsigned_tx<K>(
+    coin: &UtxoStandardCoin,
+    unspents: &[(NotaryUnspent, K)],
+    outputs: Vec<TransactionOutput>,
+) -> TransactionInputSigner {
+    let mut unsigned = coin.as_ref().transaction_preimage();
+    unsigned.inputs = unspents.iter().map(|(unspent, _)| unspent.unsigned_input()).collect();
+    unsigned.outputs = outputs;
+    unsigned
+}
+
+/// Signs the inputs of the `unsigned` transaction, `keypairs` are given in the order of the inputs
+/// along with the kind of the spent output.
+pub fn sign_unsigned_tx(
+    unsigned: TransactionInputSigner,
+    keypairs: &[(SpendKind, &KeyPair)],
+    signature_version: SignatureVersion,
+    fork_id: u32,
+) -> Result<UtxoTx, String> {
+    if keypairs.len() != unsigned.inputs.len() {
+        return Err(format!(
+            "{} key pairs are given for {} inputs",
+            keypairs.len(),
+            unsigned.inputs.len()
+        ));
+    }
+    let signed_inputs: Result<Vec<_>, _> = keypairs
+        .iter()
+        .enumerate()
+        .map(|(i, (kind, keypair))| match kind {
+            SpendKind::P2pk => p2pk_spend(&unsigned, i, keypair, signature_version, fork_id),
+            SpendKind::P2pkh => p2pkh_spend(&unsigned, i, keypair, signature_version, fork_id),
+        })
+        .collect();
+
+    let mut signed_tx: UtxoTx = unsigned.into();
+    signed_tx.inputs = signed_inputs?;
+    Ok(signed_tx)
+}
+
+/// Signs the transaction spending P2PK and P2PKH `unspents` of the notary keys to the `outputs`.
+pub fn sign_notary_tx(
+    coin: &UtxoStandardCoin,
+    unspents: &[(NotaryUnspent, &KeyPair)],
+    outputs: Vec<TransactionOutput>,
+) -> Result<UtxoTx, String> {
+    let unsigned = unsigned_tx(coin, unspents, outputs);
+    let keypairs: Vec<_> = unspents
+        .iter()
+        .map(|(unspent, keypair)| (unspent.kind, *keypair))
+        .collect();
+    sign_unsigned_tx(
+        unsigned,
+        &keypairs,
+        coin.as_ref().conf.signature_version,
+        coin.as_ref().conf.fork_id,
+    )
+}
+
+fn deduct_fee(outputs: &mut [TransactionOutput], fee_amount: u64) -> Result<(), String> {
+    let fee_output = outputs.last_mut().ok_or_else(|| "The tx has no outputs".to_owned())?;
+    if fee_amount >= fee_output.value {
+        return Err(format!(
+            "Fee {} exceeds the value {} of the output paying it",
+            fee_amount, fee_output.value
+        ));
+    }
+    fee_output.value -= fee_amount;
+    Ok(())
+}
+
+/// Signs the transaction deducting the fee from the last of the `outputs`.
+/// The fee is calculated from the size of the signed transaction.
+pub fn sign_notary_tx_with_fee(
+    coin: &UtxoStandardCoin,
+    unspents: &[(NotaryUnspent, &KeyPair)],
+    mut outputs: Vec<TransactionOutput>,
+    fee: &FeeSettings,
+    tx_fee: TxFee,
+) -> Result<SignedTx, String> {
+    // sign the tx without the fee to find out its size first
+    let estimated_tx = sign_notary_tx(coin, unspents, outputs.clone())?;
+    let tx_size = serialize(&estimated_tx).len() + unspents.len() * SIGNATURE_SIZE_MARGIN;
+    let fee_amount = fee.fee_amount(tx_fee, tx_size);
+    deduct_fee(&mut outputs, fee_amount)?;
+
+    let signed_tx = sign_notary_tx(coin, unspents, outputs)?;
+    Ok(SignedTx {
+        tx_hash: signed_tx.hash().reversed().into(),
+        tx_bytes: serialize(&signed_tx).take(),
+        fee_amount,
+    })
+}
+
+/// Builds the unsigned transaction deducting the fee from the last of the `outputs`.
+/// The fee is calculated from the size the transaction would have with the largest possible signatures.
+pub fn plan_notary_tx_with_fee(
+    coin: &UtxoStandardCoin,
+    unspents: &[(NotaryUnspent, &Public)],
+    mut outputs: Vec<TransactionOutput>,
+    fee: &FeeSettings,
+    tx_fee: TxFee,
+) -> Result<PlannedTx, String> {
+    let tx_size = max_signed_tx_size(&unsigned_tx(coin, unspents, outputs.clone()), unspents);
+    let fee_amount = fee.fee_amount(tx_fee, tx_size);
+    deduct_fee(&mut outputs, fee_amount)?;
+
+    Ok(PlannedTx {
+        unsigned: unsigned_tx(coin, unspents, outputs),
+        tx_size,
+        fee_amount,
+    })
+}
+
+fn max_signed_tx_size(unsigned: &TransactionInputSigner, unspents: &[(NotaryUnspent, &Public)]) -> usize {
+    let mut tx: UtxoTx = unsigned.clone().into();
+    for (input, (unspent, public)) in tx.inputs.iter_mut().zip(unspents.iter()) {
+        input.script_sig = vec![0; unspent.kind.max_script_sig_size(public)].into();
+    }
+    serialize(&tx).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::privkey::key_pair_from_seed;
+
+    fn output(value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            script_pubkey: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_deduct_fee_from_last_output() {
+        let mut outputs = vec![output(1000), output(5000)];
+        deduct_fee(&mut outputs, 1000).unwrap();
+        assert_eq!(outputs[0].value, 1000);
+        assert_eq!(outputs[1].value, 4000);
+
+        // the output paying the fee must keep some value
+        assert!(deduct_fee(&mut outputs, 4000).is_err());
+        assert!(deduct_fee(&mut [], 0).is_err());
+    }
+
+    #[test]
+    fn test_max_signed_tx_size() {
+        let keypair = key_pair_from_seed("test1 komodo dpow notary nodes").unwrap();
+        let unspent = |kind| NotaryUnspent {
+            tx_hash: [1; 32].into(),
+            tx_pos: 0,
+            value: 100_000,
+            height: Some(1),
+            kind,
+        };
+        let unspents = vec![
+            (unspent(SpendKind::P2pk), keypair.public()),
+            (unspent(SpendKind::P2pkh), keypair.public()),
+        ];
+        let tx = UtxoTx {
+            version: 1,
+            inputs: unspents
+                .iter()
+                .map(|(unspent, _)| {
+                    let input = unspent.unsigned_input();
+                    chain::TransactionInput {
+                        previous_output: input.previous_output,
+                        script_sig: Default::default(),
+                        sequence: input.sequence,
+                        script_witness: Vec::new(),
+                    }
+                })
+                .collect(),
+            outputs: vec![output(190_000)],
+            ..UtxoTx::default()
+        };
+        let unsigned_size = serialize(&tx).len();
+        let unsigned: TransactionInputSigner = tx.into();
+
+        // the scriptSig lengths fit a single byte, so only the scripts are added
+        let script_sigs_size = 74 + 74 + 1 + 33;
+        assert_eq!(
+            max_signed_tx_size(&unsigned, &unspents),
+            unsigned_size + script_sigs_size
+        );
+    }
+}
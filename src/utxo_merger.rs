@@ -1,203 +1,1345 @@
-use chain::constants::SEQUENCE_FINAL;
-use chain::{OutPoint, TransactionOutput};
-use coins::utxo::rpc_clients::{electrum_script_hash, ElectrumUnspent, UtxoRpcClientEnum, UtxoRpcClientOps};
-use coins::utxo::utxo_standard::{utxo_standard_coin_from_conf_and_request, UtxoStandardCoin};
-use coins::utxo::{p2pk_spend, Address, UtxoTx};
+use chain::TransactionOutput;
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use coins::utxo::Address;
 use coins::MarketCoinOps;
-use common::block_on;
-use common::mm_ctx::MmCtxBuilder;
+use common::mm_ctx::{MmArc, MmCtxBuilder};
 use common::mm_error::prelude::*;
-use common::privkey::key_pair_from_seed;
-use common::serde_derive::Deserialize;
+use common::now_ms;
+use common::serde_derive::Serialize;
 use common::serde_json::{self as json, Value as Json};
-use futures01::Future;
-use script::{Builder, UnsignedTransactionInput};
-use serialization::serialize;
-use std::time::Duration;
-
-fn unsigned_input_from_electrum(el: &ElectrumUnspent) -> UnsignedTransactionInput {
-    UnsignedTransactionInput {
-        previous_output: OutPoint {
-            hash: el.tx_hash.reversed().into(),
-            index: el.tx_pos,
-        },
-        sequence: SEQUENCE_FINAL,
-        amount: el.value,
-    }
-}
+use keys::{KeyPair, Public};
+use notary_tools_rust::alerts::{AlertEvent, Alerter};
+use notary_tools_rust::cli::{CliArgs, OfflineStep};
+use notary_tools_rust::control::{send_reply, serve_control, ControlCommand, ControlReply, ControlRequest};
+use notary_tools_rust::dry_run::{write_dry_run_report, DryRunInput};
+use notary_tools_rust::fee::{FeeSettings, TxFee};
+use notary_tools_rust::headers::{spawn_header_subscription, tcp_electrum_servers, HeaderEvent};
+use notary_tools_rust::maturity::Maturity;
+use notary_tools_rust::merger_conf::{MergerConfig, StartupSettings};
+use notary_tools_rust::metrics::MergerMetrics;
+use notary_tools_rust::notary_keys::{wif_prefix, NotaryKeyPair};
+use notary_tools_rust::notary_rpc::{coin_address, pubkey_address, NotaryRpcOps, NotaryUnspent, SpendKind};
+use notary_tools_rust::offline::{read_json_file, write_json_file, PlanInput, PlannedMerge, SignedMerge};
+use notary_tools_rust::reload::spawn_reload_triggers;
+use notary_tools_rust::rpc_failover::{activate_with_failover, FailoverRpc};
+use notary_tools_rust::scheduler::Scheduler;
+use notary_tools_rust::sent_txs::{SentTx, SentTxStore, SpentOutpoint};
+use notary_tools_rust::tx_builder::{plan_notary_tx_with_fee, sign_notary_tx_with_fee};
+use notary_tools_rust::MainError;
+use rpc::v1::types::H256 as H256Json;
+use script::{Builder, Script};
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info, info_span, warn};
 
-#[derive(Debug, Deserialize)]
-struct CoinConf {
-    ticker: String,
+const DEFAULT_CONF_PATH: &str = "./merger.json";
+/// How long the merges still running on exit are waited for, the abandoned ones may never finish.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+struct MergerCoin {
+    coin: UtxoStandardCoin,
+    /// The notary RPC calls go through it instead of the coin RPC client.
+    rpc: FailoverRpc,
+    /// The `mm_conf` and `activation_command` the coin is activated with, the coin is reused on reload
+    /// unless they change.
+    mm_conf: Json,
     activation_command: Json,
     output_threshold: u64,
-    mm_conf: Json,
+    maturity: Maturity,
+    min_inputs: usize,
+    fee: FeeSettings,
+    max_tx_inputs: usize,
+    max_tx_size: usize,
+    wif_prefix: u8,
+    to_address: Address,
+    timeout: Duration,
+    interval: Duration,
+    /// The TCP Electrum servers to subscribe to the block headers of, empty if the coin is merged at the interval only.
+    header_servers: Vec<String>,
+    min_notarization_utxos: Option<usize>,
+    large_merge_value: Option<u64>,
+    /// Kept on reload, so the reloaded coin is not merged along with the running merge.
+    state: Arc<CoinState>,
 }
 
-#[derive(Debug, Deserialize)]
-struct MergerConfig {
-    seeds: Vec<String>,
-    send_to_address: String,
-    coins: Vec<CoinConf>,
+impl MergerCoin {
+    fn batch_limits(&self) -> BatchLimits {
+        BatchLimits {
+            min_inputs: self.min_inputs,
+            max_tx_inputs: self.max_tx_inputs,
+            max_tx_size: self.max_tx_size,
+        }
+    }
 }
 
-#[derive(Debug)]
-enum MainError {
-    ConfFileRead(std::io::Error),
-    ConfSerde(json::Error),
-    KeysError(keys::Error),
-    String(String),
+/// The outcome of the recent merges reported by the control API.
+#[derive(Clone, Default, Serialize)]
+struct CoinStatus {
+    /// UNIX timestamps in seconds.
+    last_run: Option<u64>,
+    last_success: Option<u64>,
+    eligible_unspents: usize,
+    eligible_value: u64,
 }
 
-impl From<std::io::Error> for MainError {
-    fn from(err: std::io::Error) -> MainError { MainError::ConfFileRead(err) }
+#[derive(Default)]
+struct CoinState {
+    /// Set while the merge of the coin is running, including the abandoned merges that haven't finished yet.
+    busy: AtomicBool,
+    /// Set by the control API to skip the scheduled merges.
+    paused: AtomicBool,
+    status: Mutex<CoinStatus>,
 }
 
-impl From<json::Error> for MainError {
-    fn from(err: json::Error) -> MainError { MainError::ConfSerde(err) }
+impl CoinState {
+    fn status(&self) -> MutexGuard<CoinStatus> { self.status.lock().unwrap_or_else(PoisonError::into_inner) }
 }
 
-impl From<keys::Error> for MainError {
-    fn from(err: keys::Error) -> MainError { MainError::KeysError(err) }
+/// Clears the `busy` flag of the coin when the merge finishes, even if it panics.
+struct BusyGuard(Arc<CoinState>);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) { self.0.busy.store(false, Ordering::Release); }
 }
 
-impl From<String> for MainError {
-    fn from(err: String) -> MainError { MainError::String(err) }
+/// The state shared by the merge tasks of all the coins.
+struct MergeShared {
+    keypairs: Vec<NotaryKeyPair>,
+    sent_txs: Arc<SentTxStore>,
+    metrics: Arc<MergerMetrics>,
+    alerter: Alerter,
+    args: CliArgs,
 }
 
-fn main() -> Result<(), MmError<MainError>> {
-    let conf_path = "./merger.json";
-    let content = std::fs::read_to_string(conf_path)?;
-    let conf: MergerConfig = json::from_str(&content)?;
+struct MergeTx {
+    tx_hash: H256Json,
+    tx_bytes: Vec<u8>,
+    output_amount: u64,
+    fee_amount: u64,
+}
 
-    let to_address: Address = conf.send_to_address.parse()?;
-    let keypairs: Result<Vec<_>, _> = conf.seeds.iter().map(|seed| key_pair_from_seed(&seed)).collect();
-    let keypairs = keypairs?;
+/// The single output receiving the whole value of the `unspents`, the fee is deducted from it later.
+fn merge_output<K>(unspents: &[(NotaryUnspent, K)], script_pubkey: &Script) -> TransactionOutput {
+    TransactionOutput {
+        value: unspents.iter().map(|(unspent, _)| unspent.value).sum(),
+        script_pubkey: script_pubkey.to_bytes(),
+    }
+}
 
-    let ctx = MmCtxBuilder::default().into_mm_arc();
+/// Builds the transaction merging `unspents` into a single output.
+/// The fee is calculated from the size of the signed transaction.
+fn build_merge_tx(
+    merger_coin: &MergerCoin,
+    unspents: &[(NotaryUnspent, &KeyPair)],
+    script_pubkey: &Script,
+    tx_fee: TxFee,
+) -> Result<MergeTx, String> {
+    let output = merge_output(unspents, script_pubkey);
+    let input_value = output.value;
+    let signed = sign_notary_tx_with_fee(&merger_coin.coin, unspents, vec![output], &merger_coin.fee, tx_fee)?;
+    Ok(MergeTx {
+        tx_hash: signed.tx_hash,
+        tx_bytes: signed.tx_bytes,
+        output_amount: input_value - signed.fee_amount,
+        fee_amount: signed.fee_amount,
+    })
+}
 
-    // init with dummy privkey as signing is done separately
-    let coins: Result<Vec<(UtxoStandardCoin, u64)>, String> = conf
-        .coins
-        .iter()
-        .map(|coin| {
-            Ok((
-                block_on(utxo_standard_coin_from_conf_and_request(
-                    &ctx,
-                    &coin.ticker,
-                    &coin.mm_conf,
-                    &coin.activation_command,
-                    &[1; 32],
-                ))?,
-                coin.output_threshold,
-            ))
-        })
-        .collect();
-    let coins = coins?;
+/// The limits of a single merge transaction of the coin.
+#[derive(Clone, Copy)]
+struct BatchLimits {
+    min_inputs: usize,
+    max_tx_inputs: usize,
+    max_tx_size: usize,
+}
 
+/// Builds the merge transaction spending the largest batch from the beginning of `unspents_len` unspents
+/// allowed by the coin limits. `build` returns the size of the transaction spending the given number of unspents.
+/// Returns the number of spent unspents along with the transaction,
+/// `None` if the transaction of `min_inputs` unspents already exceeds `max_tx_size`.
+fn build_merge_batch<T>(
+    limits: BatchLimits,
+    unspents_len: usize,
+    build: impl Fn(usize) -> Result<(usize, T), String>,
+) -> Result<Option<(usize, T)>, String> {
+    // spread the unspents evenly instead of leaving a small remainder for the last batch
+    let batches_count = (unspents_len + limits.max_tx_inputs - 1) / limits.max_tx_inputs;
+    let even_len = (unspents_len + batches_count - 1) / batches_count;
+    let mut batch_len = even_len.max(limits.min_inputs).min(limits.max_tx_inputs);
     loop {
-        for (coin, output_threshold) in coins.iter() {
-            let electrum = match &coin.as_ref().rpc_client {
-                UtxoRpcClientEnum::Electrum(electrum) => electrum,
-                _ => panic!("Merger works only with Electrum client"),
-            };
-            let current_block = match electrum.get_block_count().wait() {
-                Ok(b) => b,
+        if batch_len < limits.min_inputs {
+            return Ok(None);
+        }
+        let (tx_size, tx) = build(batch_len)?;
+        if tx_size <= limits.max_tx_size {
+            return Ok(Some((batch_len, tx)));
+        }
+        if batch_len == 1 {
+            return Err(format!(
+                "Single input tx size {} exceeds the limit {}",
+                tx_size, limits.max_tx_size
+            ));
+        }
+        let shrunk_len = batch_len * limits.max_tx_size / tx_size;
+        batch_len = shrunk_len.min(batch_len - 1).max(1);
+    }
+}
+
+/// The unspents eligible for merging along with the keys they are locked by.
+struct EligibleUnspents<'a, K> {
+    unspents: Vec<(NotaryUnspent, &'a K)>,
+    /// Set if some of the unspents couldn't be checked, so the cycle is not considered successful.
+    failed: bool,
+    /// The number of P2PK unspents below the `output_threshold` of every key its unspents are listed for.
+    notarization_utxos: Vec<(&'a K, usize)>,
+}
+
+/// Lists the unspents of the `keys` eligible for merging, fails if the block number can't be obtained.
+/// The list is left empty if it has fewer than `min_inputs` unspents.
+fn eligible_unspents<'a, K>(
+    merger_coin: &MergerCoin,
+    keys: &'a [K],
+    public: impl Fn(&K) -> &Public,
+    sent_txs: &SentTxStore,
+    metrics: &MergerMetrics,
+) -> Result<EligibleUnspents<'a, K>, ()> {
+    let coin = &merger_coin.coin;
+    let ticker = coin.ticker();
+    let rpc_client = &merger_coin.rpc;
+    let timer = metrics.rpc_timer(ticker, "block_count");
+    let current_block = match rpc_client.block_count() {
+        Ok(b) => b,
+        Err(e) => {
+            error!(error = %e, "Failed to get the block number");
+            metrics.merge_failed(ticker, "block_count");
+            return Err(());
+        },
+    };
+    timer.observe_duration();
+    debug!(current_block, "Got the block number");
+    metrics
+        .block_height
+        .with_label_values(&[ticker])
+        .set(current_block as i64);
+    let mut failed = false;
+
+    if let Err(e) = sent_txs.reconcile(ticker, rpc_client, current_block) {
+        error!(error = %e, "Failed to update the sent transactions");
+        metrics.merge_failed(ticker, "reconcile");
+        failed = true;
+    }
+    let pending_inputs = sent_txs.pending_inputs(ticker);
+
+    let mut eligible = vec![];
+    let mut notarization_utxos = vec![];
+    for key in keys.iter() {
+        let pubkey = public(key);
+        let span = info_span!("pubkey", pubkey = %pubkey);
+        let _entered = span.enter();
+
+        let address = match pubkey_address(coin, pubkey) {
+            Ok(a) => a,
+            Err(e) => {
+                error!(error = %e, "Failed to get the address of the public key");
+                metrics.merge_failed(ticker, "address");
+                failed = true;
+                continue;
+            },
+        };
+
+        let decimals = coin.as_ref().decimals;
+        for kind in SpendKind::ALL.iter().copied() {
+            let timer = metrics.rpc_timer(ticker, "pubkey_unspents");
+            let mut unspents = match rpc_client.pubkey_unspents(kind, pubkey, &address, decimals, current_block) {
+                Ok(u) => u,
                 Err(e) => {
-                    println!("Error {} on getting block number for the coin {}", e, coin.ticker());
+                    error!(error = %e, ?kind, "Failed to get unspents");
+                    metrics.merge_failed(ticker, "unspents");
+                    failed = true;
                     continue;
                 },
             };
-            let mut unspents_with_priv = vec![];
-            for keypair in keypairs.iter() {
-                let script = Builder::build_p2pk(keypair.public());
-                let hash = electrum_script_hash(&script);
-                let hash_str = hex::encode(hash);
-
-                let unspents = match electrum.scripthash_list_unspent(&hash_str).wait() {
-                    Ok(u) => u,
+            timer.observe_duration();
+            let mempool = unspents.iter().filter(|unspent| unspent.in_mempool()).count();
+            debug!(count = unspents.len(), mempool, ?kind, "Got unspents");
+            if kind == SpendKind::P2pk {
+                let small = unspents
+                    .iter()
+                    .filter(|unspent| unspent.value < merger_coin.output_threshold)
+                    .count();
+                notarization_utxos.push((key, small));
+            }
+
+            // the mempool is checked only if there is something to merge
+            let has_candidates = unspents
+                .iter()
+                .any(|unspent| unspent.value >= merger_coin.output_threshold && !unspent.in_mempool());
+            if has_candidates {
+                let timer = metrics.rpc_timer(ticker, "mempool_spent_outpoints");
+                let mempool_spent = match rpc_client.mempool_spent_outpoints(kind, pubkey) {
+                    Ok(s) => s,
                     Err(e) => {
-                        println!("Error {} on getting unspents for public key {}", e, keypair.public());
+                        error!(error = %e, ?kind, "Failed to check the mempool spends, skipping the unspents");
+                        metrics.merge_failed(ticker, "mempool");
+                        failed = true;
                         continue;
                     },
                 };
-                unspents_with_priv.extend(unspents.into_iter().map(|u| (u, keypair)));
+                timer.observe_duration();
+                let listed = unspents.len();
+                unspents.retain(|unspent| !mempool_spent.contains(&SpentOutpoint::from(unspent)));
+                if unspents.len() < listed {
+                    info!(
+                        excluded = listed - unspents.len(),
+                        ?kind,
+                        "Excluded the unspents already spent in the mempool"
+                    );
+                }
+            }
+            eligible.extend(unspents.into_iter().map(|u| (u, key)));
+        }
+    }
+
+    eligible.retain(|(unspent, _)| {
+        if unspent.value < merger_coin.output_threshold || pending_inputs.contains(&SpentOutpoint::from(unspent)) {
+            return false;
+        }
+        match merger_coin.maturity.is_mature(rpc_client, unspent, current_block) {
+            Ok(is_mature) => is_mature,
+            Err(e) => {
+                error!(error = %e, tx_hash = ?unspent.tx_hash, "Failed to check the unspent maturity");
+                metrics.merge_failed(ticker, "maturity");
+                failed = true;
+                false
+            },
+        }
+    });
+
+    let eligible_value: u64 = eligible.iter().map(|(unspent, _)| unspent.value).sum();
+    metrics
+        .eligible_unspents
+        .with_label_values(&[ticker])
+        .set(eligible.len() as i64);
+    metrics
+        .eligible_value
+        .with_label_values(&[ticker])
+        .set(eligible_value as i64);
+    {
+        let mut status = merger_coin.state.status();
+        status.eligible_unspents = eligible.len();
+        status.eligible_value = eligible_value;
+    }
+
+    if eligible.len() < merger_coin.min_inputs {
+        info!(eligible = eligible.len(), "Not enough eligible unspents, skipping");
+        eligible.clear();
+    }
+    Ok(EligibleUnspents {
+        unspents: eligible,
+        failed,
+        notarization_utxos,
+    })
+}
+
+/// Merges the eligible unspents of the notary keys, one transaction per batch of at most `max_tx_inputs` inputs.
+/// Returns whether the merge has completed without errors.
+fn merge_coin(
+    merger_coin: &MergerCoin,
+    keypairs: &[NotaryKeyPair],
+    sent_txs: &SentTxStore,
+    metrics: &MergerMetrics,
+    alerter: &Alerter,
+    args: &CliArgs,
+) -> bool {
+    let coin = &merger_coin.coin;
+    let ticker = coin.ticker();
+    let span = info_span!("merge", ticker);
+    let _entered = span.enter();
+
+    let rpc_client = &merger_coin.rpc;
+    let eligible = match eligible_unspents(merger_coin, keypairs, |keypair| keypair.public(), sent_txs, metrics) {
+        Ok(e) => e,
+        Err(()) => {
+            alerter.rpc_reachable(ticker, false);
+            return false;
+        },
+    };
+    alerter.rpc_reachable(ticker, true);
+    if let Some(threshold) = merger_coin.min_notarization_utxos {
+        for (keypair, count) in eligible.notarization_utxos.iter() {
+            alerter.notarization_utxos(ticker, &keypair.public().to_string(), *count, threshold);
+        }
+    }
+    // the cycle is considered successful if none of the steps fails
+    let mut failed = eligible.failed;
+    let unspents_with_priv: Vec<(NotaryUnspent, &KeyPair)> = eligible
+        .unspents
+        .into_iter()
+        .map(|(unspent, keypair)| (unspent, &**keypair))
+        .collect();
+    if unspents_with_priv.is_empty() {
+        return !failed;
+    }
+
+    let to_address = &merger_coin.to_address;
+    let script_pubkey = Builder::build_p2pkh(&to_address.hash);
+    let tx_fee = merger_coin.fee.tx_fee(&merger_coin.rpc, coin.as_ref().decimals);
+
+    let mut remaining = &unspents_with_priv[..];
+    let mut batch_index = 0;
+    while remaining.len() >= merger_coin.min_inputs {
+        batch_index += 1;
+        let span = info_span!("batch", batch = batch_index);
+        let _entered = span.enter();
+
+        let batch = build_merge_batch(merger_coin.batch_limits(), remaining.len(), |len| {
+            let merge_tx = build_merge_tx(merger_coin, &remaining[..len], &script_pubkey, tx_fee)?;
+            Ok((merge_tx.tx_bytes.len(), merge_tx))
+        });
+        let (batch_len, merge_tx) = match batch {
+            Ok(Some(b)) => b,
+            Ok(None) => {
+                warn!(
+                    min_inputs = merger_coin.min_inputs,
+                    "The transaction of min_inputs unspents exceeds max_tx_size, skipping the merge"
+                );
+                break;
+            },
+            Err(e) => {
+                error!(error = %e, "Failed to build the merge transaction");
+                metrics.merge_failed(ticker, "build");
+                return false;
+            },
+        };
+        let (batch, rest) = remaining.split_at(batch_len);
+        remaining = rest;
+
+        let hex = hex::encode(&merge_tx.tx_bytes);
+        if args.dry_run {
+            let report = DryRunReport {
+                ticker: ticker.to_owned(),
+                batch: batch_index,
+                inputs: batch
+                    .iter()
+                    .map(|(unspent, keypair)| DryRunInput::new(unspent, keypair))
+                    .collect(),
+                output_address: to_address.to_string(),
+                output_amount: merge_tx.output_amount,
+                fee: merge_tx.fee_amount,
+                tx_size: merge_tx.tx_bytes.len(),
+                tx_hex: hex,
+            };
+            if let Err(e) = write_dry_run_report(&report, args.dry_run_output.as_deref()) {
+                error!(error = %e, "Failed to write the dry run report");
             }
+            continue;
+        }
+
+        let timer = metrics.rpc_timer(ticker, "send_raw_tx");
+        let send_result = rpc_client.send_raw_tx(&merge_tx.tx_bytes);
+        timer.observe_duration();
+        match send_result {
+            Ok(hash) => {
+                info!(
+                    tx_hash = %hash,
+                    inputs = batch.len(),
+                    output_amount = merge_tx.output_amount,
+                    fee = merge_tx.fee_amount,
+                    "Sent the merge transaction"
+                );
+                metrics.merges_sent.with_label_values(&[ticker]).inc();
+                if merger_coin
+                    .large_merge_value
+                    .map_or(false, |value| merge_tx.output_amount >= value)
+                {
+                    let event = AlertEvent::LargeMergeSent {
+                        tx_hash: hash.clone(),
+                        value: merge_tx.output_amount,
+                    };
+                    alerter.notify(ticker, event);
+                }
+                let inputs = batch.iter().map(|(unspent, _)| SpentOutpoint::from(unspent)).collect();
+                if let Err(e) = sent_txs.add(SentTx::pending(ticker, merge_tx.tx_hash, inputs)) {
+                    error!(error = %e, tx_hash = %hash, "Failed to save the sent transaction");
+                    metrics.merge_failed(ticker, "save_state");
+                    failed = true;
+                }
+            },
+            Err(e) => {
+                error!(error = %e, tx_hash = ?merge_tx.tx_hash, "Failed to send the merge transaction");
+                debug!(tx_hex = %hex, "Rejected merge transaction");
+                metrics.merge_failed(ticker, "send");
+                failed = true;
+            },
+        }
+    }
+
+    if !remaining.is_empty() {
+        info!(remaining = remaining.len(), "Unspents are left for the next cycle");
+    }
+    !failed
+}
+
+/// Plans the merges of the eligible unspents of the `keys` like `merge_coin` does, but leaves them unsigned.
+fn plan_coin<K>(
+    merger_coin: &MergerCoin,
+    keys: &[K],
+    public: impl Fn(&K) -> &Public,
+    sent_txs: &SentTxStore,
+    metrics: &MergerMetrics,
+) -> Vec<PlannedMerge> {
+    let coin = &merger_coin.coin;
+    let ticker = coin.ticker();
+    let span = info_span!("plan", ticker);
+    let _entered = span.enter();
+
+    let eligible = match eligible_unspents(merger_coin, keys, &public, sent_txs, metrics) {
+        Ok(e) if !e.unspents.is_empty() => e,
+        _ => return Vec::new(),
+    };
+    let unspents: Vec<(NotaryUnspent, &Public)> = eligible
+        .unspents
+        .into_iter()
+        .map(|(unspent, key)| (unspent, public(key)))
+        .collect();
+    let to_address = &merger_coin.to_address;
+    let script_pubkey = Builder::build_p2pkh(&to_address.hash);
+    let tx_fee = merger_coin.fee.tx_fee(&merger_coin.rpc, coin.as_ref().decimals);
+
+    let mut planned = Vec::new();
+    let mut remaining = &unspents[..];
+    while remaining.len() >= merger_coin.min_inputs {
+        let batch_index = planned.len() + 1;
+        let span = info_span!("batch", batch = batch_index);
+        let _entered = span.enter();
+
+        let batch = build_merge_batch(merger_coin.batch_limits(), remaining.len(), |len| {
+            let unspents = &remaining[..len];
+            let output = merge_output(unspents, &script_pubkey);
+            let planned_tx = plan_notary_tx_with_fee(coin, unspents, vec![output], &merger_coin.fee, tx_fee)?;
+            Ok((planned_tx.tx_size, planned_tx))
+        });
+        let (batch_len, planned_tx) = match batch {
+            Ok(Some(b)) => b,
+            Ok(None) => {
+                warn!(
+                    min_inputs = merger_coin.min_inputs,
+                    "The transaction of min_inputs unspents exceeds max_tx_size, skipping the merge"
+                );
+                break;
+            },
+            Err(e) => {
+                error!(error = %e, "Failed to build the merge transaction");
+                break;
+            },
+        };
+        let (batch, rest) = remaining.split_at(batch_len);
+        remaining = rest;
+        // the previous transactions let the signing host check the input values
+        let inputs: Result<Vec<_>, String> = batch
+            .iter()
+            .map(|(unspent, public)| {
+                let prev_tx = merger_coin.rpc.tx_bytes(&unspent.tx_hash)?;
+                Ok(PlanInput::new(unspent, public, &prev_tx))
+            })
+            .collect();
+        let inputs = match inputs {
+            Ok(i) => i,
+            Err(e) => {
+                error!(error = %e, "Failed to get the previous transactions of the inputs");
+                break;
+            },
+        };
+
+        info!(
+            inputs = batch.len(),
+            fee = planned_tx.fee_amount,
+            "Planned the merge transaction"
+        );
+        planned.push(PlannedMerge::new(
+            coin,
+            ticker,
+            batch_index,
+            inputs,
+            planned_tx,
+            to_address.to_string(),
+            merger_coin.wif_prefix,
+        ));
+    }
+    planned
+}
+
+/// Runs `merge_coin` on the blocking pool once a concurrency permit is acquired.
+/// A merge running longer than the coin timeout is abandoned: it can't be cancelled while blocked on the RPC,
+/// so it's left running in the background and the coin is skipped until it finishes.
+/// The permit is released on the timeout, so a stuck coin doesn't hold back the others,
+/// while the busy flag held by the abandoned merge keeps the coin from being merged again until it finishes.
+/// The alerter gets the result of every merge once, from the task running it.
+async fn spawn_merge(merger_coin: Arc<MergerCoin>, shared: Arc<MergeShared>, permits: Arc<Semaphore>) {
+    let ticker = merger_coin.coin.ticker().to_owned();
+    let _permit = match permits.acquire_owned().await {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    if merger_coin.state.busy.swap(true, Ordering::AcqRel) {
+        warn!(%ticker, "The previous merge is still running, skipping");
+        shared.metrics.merge_skipped(&ticker);
+        return;
+    }
+
+    let guard = BusyGuard(merger_coin.state.clone());
+    let task_shared = shared.clone();
+    let task_coin = merger_coin.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        let _guard = guard;
+        let ticker = task_coin.coin.ticker();
+        // the merge is reported here, as it may finish after the timeout has been reported
+        let merged = panic::catch_unwind(AssertUnwindSafe(|| {
+            merge_coin(
+                &task_coin,
+                &task_shared.keypairs,
+                &task_shared.sent_txs,
+                &task_shared.metrics,
+                &task_shared.alerter,
+                &task_shared.args,
+            )
+        }));
+        let succeeded = merged.unwrap_or_else(|_| {
+            error!(ticker, "The merge panicked");
+            task_shared.metrics.merge_failed(ticker, "panic");
+            false
+        });
+        task_coin.rpc.log_usage(ticker);
+        task_shared.alerter.merge_finished(ticker, succeeded);
+        let now = now_ms() / 1000;
+        let mut status = task_coin.state.status();
+        status.last_run = Some(now);
+        if succeeded {
+            status.last_success = Some(now);
+            task_shared.metrics.cycle_succeeded(ticker);
+        }
+    });
+    match tokio::time::timeout(merger_coin.timeout, task).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => error!(%ticker, error = %e, "The merge task failed"),
+        Err(_) => {
+            error!(
+                %ticker,
+                secs = merger_coin.timeout.as_secs(),
+                "The merge timed out, leaving it in the background"
+            );
+            shared.metrics.merge_failed(&ticker, "timeout");
+        },
+    }
+}
 
-            unspents_with_priv.retain(|(unspent, _)| {
-                let value_match = unspent.value >= *output_threshold;
-                let is_mature = match unspent.height {
-                    Some(tx_height) => current_block - tx_height > 100,
-                    None => false,
+/// Merges all the coins concurrently, returns once every merge has finished or timed out.
+async fn merge_cycle(coins: &[Arc<MergerCoin>], shared: &Arc<MergeShared>, permits: &Arc<Semaphore>) {
+    let tasks: Vec<_> = coins
+        .iter()
+        .map(|merger_coin| tokio::spawn(spawn_merge(merger_coin.clone(), shared.clone(), permits.clone())))
+        .collect();
+    for task in tasks {
+        if let Err(e) = task.await {
+            error!(error = %e, "The merge task failed");
+        }
+    }
+}
+
+/// Starts the header subscription of the coin if it has TCP Electrum servers.
+/// Returns the flag stopping the subscription once set.
+fn subscribe_headers(
+    merger_coin: &MergerCoin,
+    events_tx: &UnboundedSender<(String, HeaderEvent)>,
+) -> Option<Arc<AtomicBool>> {
+    if merger_coin.header_servers.is_empty() {
+        return None;
+    }
+    let ticker = merger_coin.coin.ticker().to_owned();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let on_event = {
+        let ticker = ticker.clone();
+        let stopped = stopped.clone();
+        let events_tx = events_tx.clone();
+        move |event: HeaderEvent| !stopped.load(Ordering::Acquire) && events_tx.send((ticker.clone(), event)).is_ok()
+    };
+    match spawn_header_subscription(ticker, merger_coin.header_servers.clone(), on_event) {
+        Ok(()) => Some(stopped),
+        Err(e) => {
+            warn!(ticker = merger_coin.coin.ticker(), error = %e, "Failed to start the header subscription");
+            None
+        },
+    }
+}
+
+/// Loads the modified config and builds its coins, activating only the new coins and the ones with changed
+/// `mm_conf` or `activation_command`. Nothing is changed if it fails, so the running coins are kept.
+/// Returns the coins, the tickers of the removed coins and the jitter.
+async fn reload_coins(
+    shared: Arc<MergeShared>,
+    ctx: MmArc,
+    running: Vec<Arc<MergerCoin>>,
+    startup: StartupSettings,
+) -> Result<(Vec<MergerCoin>, Vec<String>, Duration), String> {
+    // the coins are activated by blocking calls
+    let task = tokio::task::spawn_blocking(move || -> Result<(Vec<MergerCoin>, Vec<String>, Duration), String> {
+        let mut conf = MergerConfig::load(&shared.args.conf_path).map_err(|e| format!("{:?}", e))?;
+        conf.retain_coins(&shared.args.selected_coins())
+            .map_err(|e| format!("{:?}", e))?;
+        if conf.startup_settings() != startup {
+            warn!(
+                "state_path, metrics_addr, control_addr, max_concurrent_coins and alerts are applied only on restart"
+            );
+        }
+        let (coins, diff) = merger_coins(&conf, &shared.args, &ctx, &running)?;
+        debug!(kept = ?diff.kept, activated = ?diff.activated, dropped = ?diff.dropped, "Built the reloaded coins");
+        Ok((coins, diff.dropped, Duration::from_secs(conf.jitter_secs)))
+    });
+    task.await.map_err(|e| e.to_string())?
+}
+
+/// The coin state reported by the `status` control command.
+#[derive(Serialize)]
+struct CoinStatusReport {
+    ticker: String,
+    paused: bool,
+    busy: bool,
+    /// Whether the coin is merged on the new blocks rather than at the interval.
+    subscribed: bool,
+    interval_secs: u64,
+    #[serde(flatten)]
+    status: CoinStatus,
+    /// The hashes of the sent merge transactions that are not confirmed yet.
+    pending_merges: Vec<H256Json>,
+}
+
+fn find_coin<'a>(coins: &'a [Arc<MergerCoin>], ticker: &str) -> Result<&'a Arc<MergerCoin>, String> {
+    coins
+        .iter()
+        .find(|merger_coin| merger_coin.coin.ticker() == ticker)
+        .ok_or_else(|| format!("Unknown coin {}", ticker))
+}
+
+fn coins_status(
+    coins: &[Arc<MergerCoin>],
+    subscribed: &HashSet<String>,
+    sent_txs: &SentTxStore,
+) -> Vec<CoinStatusReport> {
+    coins
+        .iter()
+        .map(|merger_coin| {
+            let ticker = merger_coin.coin.ticker();
+            CoinStatusReport {
+                ticker: ticker.to_owned(),
+                paused: merger_coin.state.paused.load(Ordering::Acquire),
+                busy: merger_coin.state.busy.load(Ordering::Acquire),
+                subscribed: subscribed.contains(ticker),
+                interval_secs: merger_coin.interval.as_secs(),
+                status: merger_coin.state.status().clone(),
+                pending_merges: sent_txs.pending_txs(ticker),
+            }
+        })
+        .collect()
+}
+
+/// Replies with the merges `merge_coin` would send, planned by the same selection code without signing.
+async fn plan_for_control(merger_coin: Arc<MergerCoin>, shared: Arc<MergeShared>, reply: ControlReply) {
+    let task = tokio::task::spawn_blocking(move || {
+        let planned = plan_coin(
+            &merger_coin,
+            &shared.keypairs,
+            |keypair| keypair.public(),
+            &shared.sent_txs,
+            &shared.metrics,
+        );
+        json::to_value(planned).map_err(|e| e.to_string())
+    });
+    let result = task.await.map_err(|e| e.to_string()).and_then(|planned| planned);
+    send_reply(&reply, result);
+}
+
+/// Merges every coin at its own interval until the process is stopped.
+/// The next merge of a coin is scheduled when the previous one starts, a merge still running by then is skipped.
+/// The coins subscribed to the Electrum block headers are merged when their tip advances instead,
+/// as new unspents can become mature only then, but not more often than at the interval.
+/// The interval is kept as the fallback while the subscription is lost.
+/// The coins are updated when the config file is modified or on SIGHUP. The reload runs in the background
+/// and its result is applied once ready, the merges and the `control` requests go on meanwhile.
+/// The `control` requests are handled between the merges, the channel is closed if the control API is not served.
+async fn run_scheduled(
+    mut coins: Vec<Arc<MergerCoin>>,
+    shared: Arc<MergeShared>,
+    permits: Arc<Semaphore>,
+    ctx: MmArc,
+    startup: StartupSettings,
+    jitter: Duration,
+    mut control: UnboundedReceiver<ControlRequest>,
+) {
+    // the senders are kept alive by this function and the reload triggers, so `recv` never returns `None`
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let (reloads_tx, mut reloads) = mpsc::unbounded_channel();
+    let pending_reload_tx = reloads_tx.clone();
+    spawn_reload_triggers(shared.args.conf_path.clone(), reloads_tx);
+    let (reloaded_tx, mut reloaded) = mpsc::unbounded_channel();
+    // a reload requested while the previous one is running is repeated once that one is applied
+    let mut reloading = false;
+    let mut reload_pending = false;
+
+    let mut scheduler = Scheduler::new(jitter);
+    // the stop flags of the header subscriptions by the ticker
+    let mut subscriptions = HashMap::new();
+    let mut subscribed = HashSet::new();
+    // when the last merge triggered by the headers has started by the ticker
+    let mut header_merges: HashMap<String, Instant> = HashMap::new();
+    let now = Instant::now();
+    for merger_coin in coins.iter() {
+        let ticker = merger_coin.coin.ticker();
+        scheduler.insert(ticker, merger_coin.interval, now);
+        if let Some(stop) = subscribe_headers(merger_coin, &events_tx) {
+            subscriptions.insert(ticker.to_owned(), stop);
+        }
+    }
+
+    let start_merge = |merger_coin: &Arc<MergerCoin>| {
+        tokio::spawn(spawn_merge(merger_coin.clone(), shared.clone(), permits.clone()));
+    };
+    let start_reload = |coins: &[Arc<MergerCoin>]| {
+        let reload = reload_coins(shared.clone(), ctx.clone(), coins.to_vec(), startup.clone());
+        let reloaded_tx = reloaded_tx.clone();
+        tokio::spawn(async move { reloaded_tx.send(reload.await).ok() });
+    };
+    loop {
+        // wait for a reload if there are no coins
+        let next_run = scheduler
+            .next_run()
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_run.into()) => {
+                for ticker in scheduler.take_due(Instant::now()) {
+                    let merger_coin = match coins.iter().find(|merger_coin| merger_coin.coin.ticker() == ticker) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    if merger_coin.state.paused.load(Ordering::Acquire) {
+                        debug!(%ticker, "The coin is paused, skipping the scheduled merge");
+                        continue;
+                    }
+                    if subscribed.contains(&ticker) {
+                        debug!(%ticker, "Subscribed to the block headers, skipping the scheduled merge");
+                        continue;
+                    }
+                    debug!(%ticker, next_in_secs = merger_coin.interval.as_secs(), "Starting the scheduled merge");
+                    start_merge(merger_coin);
+                }
+            },
+            Some((ticker, event)) = events.recv() => {
+                let merger_coin = match coins.iter().find(|merger_coin| merger_coin.coin.ticker() == ticker) {
+                    Some(c) => c,
+                    None => continue,
                 };
-                value_match && is_mature
-            });
+                match event {
+                    HeaderEvent::Tip(height) => {
+                        if subscribed.insert(ticker.clone()) {
+                            info!(%ticker, "Merging on the new blocks");
+                        }
+                        if merger_coin.state.paused.load(Ordering::Acquire) {
+                            debug!(%ticker, height, "The tip has advanced, but the coin is paused");
+                            continue;
+                        }
+                        let now = Instant::now();
+                        let last_merge = header_merges.get(&ticker).copied();
+                        if last_merge.map_or(false, |last| now < last + merger_coin.interval) {
+                            debug!(%ticker, height, "The tip has advanced, but the last merge is too recent");
+                            continue;
+                        }
+                        debug!(%ticker, height, "The tip has advanced, starting the merge");
+                        header_merges.insert(ticker, now);
+                        start_merge(merger_coin);
+                    },
+                    HeaderEvent::Lost => {
+                        if subscribed.remove(&ticker) {
+                            warn!(
+                                %ticker,
+                                secs = merger_coin.interval.as_secs(),
+                                "The header subscription is lost, merging at the interval"
+                            );
+                        }
+                    },
+                }
+            },
+            Some(ControlRequest { command, reply }) = control.recv() => {
+                let result = match command {
+                    ControlCommand::Status => json::to_value(coins_status(&coins, &subscribed, &shared.sent_txs))
+                        .map_err(|e| e.to_string()),
+                    ControlCommand::MergeNow { ticker } => find_coin(&coins, &ticker).and_then(|merger_coin| {
+                        if merger_coin.state.paused.load(Ordering::Acquire) {
+                            return Err(format!("{} is paused", ticker));
+                        }
+                        if merger_coin.state.busy.load(Ordering::Acquire) {
+                            return Err(format!("The merge of {} is already running", ticker));
+                        }
+                        info!(%ticker, "Starting the merge requested by the control API");
+                        start_merge(merger_coin);
+                        Ok(json::json!({"started": true}))
+                    }),
+                    ControlCommand::Pause { ticker } => find_coin(&coins, &ticker).map(|merger_coin| {
+                        merger_coin.state.paused.store(true, Ordering::Release);
+                        info!(%ticker, "Paused the coin");
+                        json::json!({"paused": true})
+                    }),
+                    ControlCommand::Resume { ticker } => find_coin(&coins, &ticker).map(|merger_coin| {
+                        merger_coin.state.paused.store(false, Ordering::Release);
+                        info!(%ticker, "Resumed the coin");
+                        json::json!({"paused": false})
+                    }),
+                    ControlCommand::Plan { ticker } => match find_coin(&coins, &ticker) {
+                        Ok(merger_coin) => {
+                            tokio::spawn(plan_for_control(merger_coin.clone(), shared.clone(), reply));
+                            continue;
+                        },
+                        Err(e) => Err(e),
+                    },
+                };
+                send_reply(&reply, result);
+            },
+            Some(()) = reloads.recv() => {
+                if reloading {
+                    reload_pending = true;
+                } else {
+                    reloading = true;
+                    start_reload(&coins);
+                }
+            },
+            Some(result) = reloaded.recv() => {
+                reloading = false;
+                if std::mem::take(&mut reload_pending) {
+                    pending_reload_tx.send(()).ok();
+                }
+                let (new_coins, dropped, jitter) = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!(error = %e, "Rejected the config reload, the running config is kept");
+                        continue;
+                    },
+                };
+                let new_coins: Vec<_> = new_coins.into_iter().map(Arc::new).collect();
+                for ticker in dropped.iter().map(String::as_str) {
+                    info!(ticker, "The coin is removed from the config");
+                    scheduler.remove(ticker);
+                    subscribed.remove(ticker);
+                    header_merges.remove(ticker);
+                    if let Some(stop) = subscriptions.remove(ticker) {
+                        stop.store(true, Ordering::Release);
+                    }
+                    shared.metrics.remove_coin(ticker);
+                    shared.alerter.remove_coin(ticker);
+                }
+
+                let now = Instant::now();
+                for merger_coin in new_coins.iter() {
+                    let ticker = merger_coin.coin.ticker();
+                    let old = coins.iter().find(|old| old.coin.ticker() == ticker);
+                    if old.is_none() {
+                        info!(ticker, "The coin is added to the config");
+                    }
+                    scheduler.insert(ticker, merger_coin.interval, now);
+                    if old.map_or(false, |old| old.header_servers == merger_coin.header_servers) {
+                        continue;
+                    }
+                    subscribed.remove(ticker);
+                    if let Some(stop) = subscriptions.remove(ticker) {
+                        stop.store(true, Ordering::Release);
+                    }
+                    if let Some(stop) = subscribe_headers(merger_coin, &events_tx) {
+                        subscriptions.insert(ticker.to_owned(), stop);
+                    }
+                }
+                scheduler.set_jitter(jitter);
+                coins = new_coins;
+                info!(coins = coins.len(), "Reloaded the config");
+            },
+        }
+    }
+}
+
+/// Signs the merges planned by `utxo_merger plan`, doesn't need the coin RPC.
+/// The planned transactions must pay to the `send_to_address` of their coins in the config.
+fn sign_planned_merges(
+    conf: &mut MergerConfig,
+    args: &CliArgs,
+    plan_path: &str,
+    output: &str,
+) -> Result<(), MainError> {
+    let planned: Vec<PlannedMerge> = read_json_file(plan_path)?;
+    let wif_prefixes: Vec<_> = planned
+        .iter()
+        .map(|merge| (merge.ticker.as_str(), merge.wif_prefix))
+        .collect();
+    let keypairs = conf.keys.key_pairs(args.passphrase_stdin, &wif_prefixes)?;
+
+    let mut signed = Vec::with_capacity(planned.len());
+    for merge in planned.iter() {
+        let coin_conf = conf
+            .coins
+            .iter()
+            .find(|coin| coin.ticker == merge.ticker)
+            .ok_or_else(|| MainError::UnknownCoin(merge.ticker.clone()))?;
+        let to_address: Address = coin_conf.send_to_address(conf.send_to_address.as_ref())?.parse()?;
+        let signed_merge = merge.sign(&keypairs, &to_address, &coin_conf.fee_settings())?;
+        // the values are taken from the signed transaction, the ones of the plan are not trusted
+        info!(
+            ticker = %signed_merge.ticker,
+            batch = signed_merge.batch,
+            inputs = signed_merge.inputs.len(),
+            %to_address,
+            output_amount = signed_merge.output_amount,
+            fee = signed_merge.fee,
+            "Signed the planned merge"
+        );
+        signed.push(signed_merge);
+    }
+    write_json_file(output, &signed)?;
+    info!(count = signed.len(), %output, "Wrote the signed merges");
+    Ok(())
+}
+
+/// Broadcasts the merges signed by `utxo_merger sign` and saves them to the state like the regular cycles do.
+fn broadcast_signed_merges(coins: &[MergerCoin], sent_txs: &SentTxStore, signed_path: &str) -> Result<(), MainError> {
+    let signed: Vec<SignedMerge> = read_json_file(signed_path)?;
+    for merge in signed {
+        let span = info_span!("broadcast", ticker = %merge.ticker, batch = merge.batch);
+        let _entered = span.enter();
 
-            if unspents_with_priv.len() < 4 {
-                println!("Currently available unspents {}, skipping", unspents_with_priv.len());
+        let merger_coin = match coins
+            .iter()
+            .find(|merger_coin| merger_coin.coin.ticker() == merge.ticker)
+        {
+            Some(c) => c,
+            None => {
+                error!("The coin is not configured or not selected, skipping");
                 continue;
-            }
+            },
+        };
+        let tx_bytes = hex::decode(&merge.tx_hex).map_err(|e| format!("Invalid tx_hex: {}", e))?;
+        match merger_coin.rpc.send_raw_tx(&tx_bytes) {
+            Ok(hash) => {
+                info!(tx_hash = %hash, inputs = merge.inputs.len(), "Sent the merge transaction");
+                if let Err(e) = sent_txs.add(SentTx::pending(&merge.ticker, merge.tx_hash, merge.inputs)) {
+                    error!(error = %e, tx_hash = %hash, "Failed to save the sent transaction");
+                }
+            },
+            Err(e) => error!(error = %e, tx_hash = ?merge.tx_hash, "Failed to send the merge transaction"),
+        }
+    }
+    Ok(())
+}
 
-            let mut unsigned = coin.as_ref().transaction_preimage();
-            unsigned.inputs = unspents_with_priv
-                .iter()
-                .map(|(el, _)| unsigned_input_from_electrum(el))
-                .collect();
+/// The tickers of the running coins kept by the reload, the coins activated by it and the removed ones.
+#[derive(Debug, Default, PartialEq)]
+struct ReloadDiff {
+    kept: Vec<String>,
+    /// The new coins and the ones with changed `mm_conf` or `activation_command`.
+    activated: Vec<String>,
+    dropped: Vec<String>,
+}
 
-            let script_pubkey = Builder::build_p2pkh(&to_address.hash).to_bytes();
+/// Compares the coins of the `conf` with the `running` ones given by their ticker, `mm_conf` and `activation_command`.
+/// Fails if the `conf` is invalid, so no coin is activated and the running ones are kept.
+fn reload_diff(conf: &MergerConfig, running: &[(&str, &Json, &Json)]) -> Result<ReloadDiff, String> {
+    conf.validate()?;
 
-            let output_amount = unsigned.inputs.iter().fold(0, |cur, input| cur + input.amount - 1000);
-            let output = TransactionOutput {
-                value: output_amount,
-                script_pubkey,
+    let mut diff = ReloadDiff::default();
+    for coin in conf.coins.iter() {
+        let unchanged = running.iter().any(|(ticker, mm_conf, activation_command)| {
+            *ticker == coin.ticker && **mm_conf == coin.mm_conf && **activation_command == coin.activation_command
+        });
+        if unchanged {
+            diff.kept.push(coin.ticker.clone());
+        } else {
+            diff.activated.push(coin.ticker.clone());
+        }
+    }
+    diff.dropped = running
+        .iter()
+        .filter(|(ticker, ..)| !conf.coins.iter().any(|coin| coin.ticker == *ticker))
+        .map(|(ticker, ..)| (*ticker).to_owned())
+        .collect();
+    Ok(diff)
+}
+
+/// Builds the coins of the `conf`. The `running` coins are reused if their `mm_conf` and `activation_command`
+/// are not changed, so only the new and changed coins are activated.
+fn merger_coins(
+    conf: &MergerConfig,
+    args: &CliArgs,
+    ctx: &MmArc,
+    running: &[Arc<MergerCoin>],
+) -> Result<(Vec<MergerCoin>, ReloadDiff), String> {
+    let running_confs: Vec<_> = running
+        .iter()
+        .map(|merger_coin| {
+            (
+                merger_coin.coin.ticker(),
+                &merger_coin.mm_conf,
+                &merger_coin.activation_command,
+            )
+        })
+        .collect();
+    // fail before activating any coin
+    let diff = reload_diff(conf, &running_confs)?;
+
+    let coins = conf
+        .coins
+        .iter()
+        .map(|coin| {
+            let send_to_address = coin.send_to_address(conf.send_to_address.as_ref())?;
+            let running_coin = running
+                .iter()
+                .find(|merger_coin| merger_coin.coin.ticker() == coin.ticker);
+            let (utxo_coin, rpc) = match running_coin {
+                Some(c) if diff.kept.contains(&coin.ticker) => (c.coin.clone(), c.rpc.with_retry(coin.retry.clone())),
+                _ => activate_with_failover(
+                    ctx,
+                    &coin.ticker,
+                    &coin.mm_conf,
+                    &coin.activation_command,
+                    coin.retry.clone(),
+                )?,
+            };
+            let to_address = coin_address(&utxo_coin, &coin.ticker, send_to_address)?;
+            let header_servers = if coin.header_subscription {
+                tcp_electrum_servers(&coin.activation_command)
+            } else {
+                Vec::new()
             };
+            if coin.header_subscription && header_servers.is_empty() {
+                warn!(
+                    ticker = %coin.ticker,
+                    "No TCP Electrum servers to subscribe to the headers, merging at the interval"
+                );
+            }
+            Ok(MergerCoin {
+                coin: utxo_coin,
+                rpc,
+                mm_conf: coin.mm_conf.clone(),
+                activation_command: coin.activation_command.clone(),
+                output_threshold: coin.output_threshold,
+                maturity: Maturity::new(coin.maturity.clone()),
+                min_inputs: coin.min_inputs,
+                fee: coin.fee_settings(),
+                max_tx_inputs: coin.max_tx_inputs,
+                max_tx_size: coin.max_tx_size,
+                wif_prefix: wif_prefix(&coin.mm_conf),
+                to_address,
+                timeout: Duration::from_secs(coin.timeout_secs),
+                interval: coin.interval_secs.map(Duration::from_secs).unwrap_or(args.interval),
+                header_servers,
+                min_notarization_utxos: coin.min_notarization_utxos,
+                large_merge_value: coin.large_merge_value,
+                state: running_coin.map_or_else(Default::default, |c| c.state.clone()),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((coins, diff))
+}
 
-            unsigned.outputs = vec![output];
+/// The merge transaction that would have been broadcast if the merger was not run with `--dry-run`.
+#[derive(Debug, Serialize)]
+struct DryRunReport {
+    ticker: String,
+    /// The index of the merge transaction among the ones built for the coin during the cycle, starting from 1.
+    batch: usize,
+    inputs: Vec<DryRunInput>,
+    output_address: String,
+    output_amount: u64,
+    fee: u64,
+    tx_size: usize,
+    tx_hex: String,
+}
+
+fn main() -> Result<(), MmError<MainError>> {
+    let args = CliArgs::parse(
+        "utxo_merger",
+        "Merges mature P2PK and P2PKH unspents of the notary keys into a single output",
+        DEFAULT_CONF_PATH,
+        true,
+    )?;
+    let _log_guard = args.init_logging()?;
 
-            let signed_inputs: Result<Vec<_>, _> = unsigned
-                .inputs
+    let mut conf = MergerConfig::load(&args.conf_path)?;
+
+    conf.retain_coins(&args.selected_coins())?;
+
+    if let Some(OfflineStep::Sign { plan, output }) = &args.offline_step {
+        sign_planned_merges(&mut conf, &args, plan, output)?;
+        return Ok(());
+    }
+
+    let keypairs = match args.offline_step {
+        // the keys are needed only to sign
+        Some(_) => Vec::new(),
+        None => conf.key_pairs(args.passphrase_stdin)?,
+    };
+
+    let sent_txs = Arc::new(SentTxStore::load(&conf.state_path)?);
+    let metrics = Arc::new(MergerMetrics::new().map_err(|e| format!("Error {} on creating the metrics", e))?);
+    if let Some(addr) = &conf.metrics_addr {
+        metrics.clone().serve(addr)?;
+    }
+    let ctx = MmCtxBuilder::default().into_mm_arc();
+
+    let (coins, _) = merger_coins(&conf, &args, &ctx, &[])?;
+
+    match &args.offline_step {
+        Some(OfflineStep::Plan { output }) => {
+            if conf.pubkeys.is_empty() {
+                return Err(MainError::String("pubkeys must be set to plan the merges".into()).into());
+            }
+            let pubkeys = conf.parse_pubkeys()?;
+            let planned: Vec<_> = coins
                 .iter()
-                .enumerate()
-                .map(|(i, _)| {
-                    p2pk_spend(
-                        &unsigned,
-                        i,
-                        &unspents_with_priv[i].1,
-                        coin.as_ref().conf.signature_version,
-                        coin.as_ref().conf.fork_id,
-                    )
-                })
+                .flat_map(|merger_coin| plan_coin(merger_coin, &pubkeys, |pubkey| pubkey, &sent_txs, &metrics))
                 .collect();
+            write_json_file(output, &planned)?;
+            info!(count = planned.len(), %output, "Wrote the planned merges");
+            return Ok(());
+        },
+        Some(OfflineStep::Broadcast { signed }) => {
+            broadcast_signed_merges(&coins, &sent_txs, signed)?;
+            return Ok(());
+        },
+        Some(OfflineStep::Sign { .. }) | None => (),
+    }
 
-            let signed_inputs = match signed_inputs {
-                Ok(s) => s,
-                Err(e) => {
-                    println!(
-                        "Error {} on signing the tx {:?} for coin {}",
-                        e,
-                        unsigned,
-                        coin.ticker()
-                    );
-                    continue;
-                },
-            };
-
-            let mut signed_tx: UtxoTx = unsigned.into();
-            signed_tx.inputs = signed_inputs;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Error {} on creating the runtime", e))?;
+    let coins: Vec<_> = coins.into_iter().map(Arc::new).collect();
+    let permits = Arc::new(Semaphore::new(conf.max_concurrent_coins));
+    let once = args.once;
+    let shared = Arc::new(MergeShared {
+        keypairs,
+        sent_txs,
+        metrics,
+        alerter: Alerter::new(conf.alerts.clone()),
+        args,
+    });
 
-            let bytes = serialize(&signed_tx);
-            let hex = hex::encode(&bytes);
-            let hash = match coin.send_raw_tx(&hex).wait() {
-                Ok(h) => h,
-                Err(e) => {
-                    println!("Error {} on sending {} transaction {}", e, coin.ticker(), hex);
-                    continue;
-                },
-            };
-            println!("Sent {} transaction {}", coin.ticker(), hash);
+    if once {
+        runtime.block_on(merge_cycle(&coins, &shared, &permits));
+    } else {
+        let startup = conf.startup_settings();
+        let jitter = Duration::from_secs(conf.jitter_secs);
+        let (control_tx, control) = mpsc::unbounded_channel();
+        if let Some(addr) = &conf.control_addr {
+            serve_control(addr, control_tx)?;
         }
+        runtime.block_on(run_scheduled(coins, shared, permits, ctx, startup, jitter, control));
+    }
+    // the abandoned merges may stay blocked on the RPC, the runtime would wait for them on drop
+    runtime.shutdown_timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The size of the transaction spending `len` unspents, 100 bytes per input.
+    fn build(len: usize) -> Result<(usize, usize), String> { Ok((len * 100, len)) }
+
+    #[test]
+    fn test_build_merge_batch() {
+        let limits = BatchLimits {
+            min_inputs: 2,
+            max_tx_inputs: 400,
+            max_tx_size: 100_000,
+        };
+        // 500 unspents are spread evenly over two transactions
+        assert_eq!(build_merge_batch(limits, 500, build), Ok(Some((250, 250))));
+
+        let limits = BatchLimits {
+            max_tx_size: 10_000,
+            ..limits
+        };
+        assert_eq!(build_merge_batch(limits, 500, build), Ok(Some((100, 100))));
+    }
+
+    #[test]
+    fn test_build_merge_batch_below_min_inputs() {
+        let limits = BatchLimits {
+            min_inputs: 10,
+            max_tx_inputs: 400,
+            max_tx_size: 500,
+        };
+        // only 5 inputs fit the size limit
+        assert_eq!(build_merge_batch(limits, 20, build), Ok(None));
+
+        // the even spread doesn't go below min_inputs
+        let limits = BatchLimits {
+            min_inputs: 300,
+            max_tx_inputs: 500,
+            max_tx_size: 100_000,
+        };
+        assert_eq!(build_merge_batch(limits, 501, build), Ok(Some((300, 300))));
+    }
+
+    fn conf(coins: &str) -> MergerConfig {
+        json::from_str(&format!(
+            r#"{{"send_to_address": "RJTYiYeJ8eVvJ53n2YbrVmxWNNMVZjDGLh", "coins": {}}}"#,
+            coins
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reload_diff() {
+        let conf = conf(
+            r#"[
+                {"ticker": "RICK", "activation_command": {"method": "electrum"}, "output_threshold": 1, "mm_conf": {}},
+                {"ticker": "MORTY", "activation_command": {"method": "enable"}, "output_threshold": 1, "mm_conf": {}},
+                {"ticker": "DOC", "activation_command": {}, "output_threshold": 1, "mm_conf": {}}
+            ]"#,
+        );
+        let empty = json::json!({});
+        let electrum = json::json!({"method": "electrum"});
+        let running = [
+            ("RICK", &empty, &electrum),
+            ("MORTY", &empty, &electrum),
+            ("MARTY", &empty, &empty),
+        ];
+        let diff = reload_diff(&conf, &running).unwrap();
+        assert_eq!(diff, ReloadDiff {
+            kept: vec!["RICK".into()],
+            activated: vec!["MORTY".into(), "DOC".into()],
+            dropped: vec!["MARTY".into()],
+        });
+
+        // a changed mm_conf activates the coin again too
+        let changed = json::json!({"txfee": 1000});
+        let diff = reload_diff(&conf, &[("RICK", &changed, &electrum)]).unwrap();
+        assert_eq!(diff.activated, ["RICK", "MORTY", "DOC"]);
+        assert!(diff.kept.is_empty());
+
+        // the startup activates every coin
+        let diff = reload_diff(&conf, &[]).unwrap();
+        assert_eq!(diff.activated, ["RICK", "MORTY", "DOC"]);
+        assert!(diff.dropped.is_empty());
+    }
 
-        println!("Sleeping for 15 minutes");
-        std::thread::sleep(Duration::from_secs(15 * 60));
+    #[test]
+    fn test_reload_diff_rejects_invalid_config() {
+        let mut conf = conf(r#"[{"ticker": "RICK", "activation_command": {}, "output_threshold": 1, "mm_conf": {}}]"#);
+        conf.coins[0].min_inputs = 0;
+        let empty = json::json!({});
+        // nothing is activated or dropped, so the running coins are kept
+        let error = reload_diff(&conf, &[("RICK", &empty, &empty), ("MORTY", &empty, &empty)]).unwrap_err();
+        assert!(error.contains("min_inputs"), "{}", error);
     }
 }
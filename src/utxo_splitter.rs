@@ -0,0 +1,279 @@
+use chain::TransactionOutput;
+use coins::utxo::utxo_standard::UtxoStandardCoin;
+use coins::MarketCoinOps;
+use common::mm_ctx::MmCtxBuilder;
+use common::mm_error::prelude::*;
+use common::serde_derive::{Deserialize, Serialize};
+use common::serde_json::Value as Json;
+use keys::KeyPair;
+use notary_tools_rust::cli::{retain_coins, CliArgs};
+use notary_tools_rust::dry_run::{write_dry_run_report, DryRunInput};
+use notary_tools_rust::fee::{FeePolicy, FeeSettings, TxFee};
+use notary_tools_rust::keystore::KeysConf;
+use notary_tools_rust::maturity::{Maturity, MaturityConf};
+use notary_tools_rust::notary_keys::{wif_prefix, NotaryKeyPair};
+use notary_tools_rust::notary_rpc::{pubkey_address, NotaryRpcOps, SpendKind};
+use notary_tools_rust::tx_builder::sign_notary_tx_with_fee;
+use notary_tools_rust::{activate_coin, read_config, MainError};
+use script::Builder;
+use tracing::{debug, error, info, info_span, warn};
+
+const DEFAULT_CONF_PATH: &str = "./splitter.json";
+
+#[derive(Debug, Deserialize)]
+struct CoinConf {
+    ticker: String,
+    activation_command: Json,
+    mm_conf: Json,
+    /// The value of each notarization-sized output.
+    split_value: u64,
+    /// A split is made when a pubkey has fewer P2PK outputs not larger than `split_value`.
+    target_count: usize,
+    /// The number of `split_value` outputs created by a single split transaction.
+    split_count: usize,
+    /// Applied to the unspent being split.
+    #[serde(flatten)]
+    maturity: MaturityConf,
+    /// Defaults to `txfee` of the `mm_conf` per kB.
+    #[serde(default)]
+    fee_policy: Option<FeePolicy>,
+    /// The upper limit of the fee paid by a single split transaction.
+    #[serde(default)]
+    max_fee: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SplitterConfig {
+    #[serde(flatten)]
+    keys: KeysConf,
+    coins: Vec<CoinConf>,
+}
+
+struct SplitterCoin {
+    coin: UtxoStandardCoin,
+    split_value: u64,
+    target_count: usize,
+    split_count: usize,
+    /// `split_value * split_count`, checked not to overflow along with the change when the config is loaded.
+    split_amount: u64,
+    maturity: Maturity,
+    fee: FeeSettings,
+}
+
+/// The split transaction that would have been broadcast if the splitter was not run with `--dry-run`.
+#[derive(Debug, Serialize)]
+struct DryRunReport {
+    ticker: String,
+    input: DryRunInput,
+    split_count: usize,
+    split_value: u64,
+    change_amount: u64,
+    fee: u64,
+    tx_size: usize,
+    tx_hex: String,
+}
+
+/// Splits a larger P2PK unspent of the `keypair` into `split_count` outputs of `split_value`
+/// if the pubkey has fewer than `target_count` small P2PK outputs.
+fn split_pubkey(splitter_coin: &SplitterCoin, keypair: &KeyPair, current_block: u64, tx_fee: TxFee, args: &CliArgs) {
+    let coin = &splitter_coin.coin;
+    let span = info_span!("pubkey", pubkey = %keypair.public());
+    let _entered = span.enter();
+
+    let rpc_client = &coin.as_ref().rpc_client;
+    let script = Builder::build_p2pk(keypair.public());
+    let address = match pubkey_address(coin, keypair.public()) {
+        Ok(a) => a,
+        Err(e) => {
+            error!(error = %e, "Failed to get the address of the public key");
+            return;
+        },
+    };
+
+    let decimals = coin.as_ref().decimals;
+    // notarizations spend P2PK outputs only
+    let unspents = rpc_client.pubkey_unspents(SpendKind::P2pk, keypair.public(), &address, decimals, current_block);
+    let unspents = match unspents {
+        Ok(u) => u,
+        Err(e) => {
+            error!(error = %e, "Failed to get unspents");
+            return;
+        },
+    };
+
+    // unconfirmed outputs are counted too, so the outputs of a previous split are not duplicated
+    let small_count = unspents
+        .iter()
+        .filter(|unspent| unspent.value <= splitter_coin.split_value)
+        .count();
+    if small_count >= splitter_coin.target_count {
+        debug!(small_count, "Enough small unspents, skipping");
+        return;
+    }
+
+    // leave at least one more `split_value` for the change paying the fee
+    let split_amount = splitter_coin.split_amount;
+    let required_value = split_amount + splitter_coin.split_value;
+    let source = unspents
+        .iter()
+        .filter(|unspent| unspent.value >= required_value)
+        .filter(
+            |unspent| match splitter_coin.maturity.is_mature(rpc_client, unspent, current_block) {
+                Ok(is_mature) => is_mature,
+                Err(e) => {
+                    error!(error = %e, tx_hash = ?unspent.tx_hash, "Failed to check the unspent maturity");
+                    false
+                },
+            },
+        )
+        .min_by_key(|unspent| unspent.value);
+    let source = match source {
+        Some(s) => s.clone(),
+        None => {
+            warn!(small_count, required_value, "No mature unspent large enough to split");
+            return;
+        },
+    };
+
+    let script_pubkey = script.to_bytes();
+    let mut outputs = vec![
+        TransactionOutput {
+            value: splitter_coin.split_value,
+            script_pubkey: script_pubkey.clone(),
+        };
+        splitter_coin.split_count
+    ];
+    // the change is sent back to the same P2PK script and pays the fee
+    outputs.push(TransactionOutput {
+        value: source.value - split_amount,
+        script_pubkey,
+    });
+
+    let inputs = [(source, keypair)];
+    let signed = match sign_notary_tx_with_fee(coin, &inputs, outputs, &splitter_coin.fee, tx_fee) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = %e, "Failed to build the split transaction");
+            return;
+        },
+    };
+
+    let hex = hex::encode(&signed.tx_bytes);
+    if args.dry_run {
+        let report = DryRunReport {
+            ticker: coin.ticker().to_owned(),
+            input: DryRunInput::new(&inputs[0].0, keypair),
+            split_count: splitter_coin.split_count,
+            split_value: splitter_coin.split_value,
+            change_amount: inputs[0].0.value - split_amount - signed.fee_amount,
+            fee: signed.fee_amount,
+            tx_size: signed.tx_bytes.len(),
+            tx_hex: hex,
+        };
+        if let Err(e) = write_dry_run_report(&report, args.dry_run_output.as_deref()) {
+            error!(error = %e, "Failed to write the dry run report");
+        }
+        return;
+    }
+
+    match rpc_client.send_raw_tx(&signed.tx_bytes) {
+        Ok(hash) => info!(
+            tx_hash = %hash,
+            split_count = splitter_coin.split_count,
+            fee = signed.fee_amount,
+            "Sent the split transaction"
+        ),
+        Err(e) => {
+            error!(error = %e, tx_hash = ?signed.tx_hash, "Failed to send the split transaction");
+            debug!(tx_hex = %hex, "Rejected split transaction");
+        },
+    }
+}
+
+fn split_coin(splitter_coin: &SplitterCoin, keypairs: &[NotaryKeyPair], args: &CliArgs) {
+    let coin = &splitter_coin.coin;
+    let span = info_span!("split", ticker = coin.ticker());
+    let _entered = span.enter();
+
+    let rpc_client = &coin.as_ref().rpc_client;
+    let current_block = match rpc_client.block_count() {
+        Ok(b) => b,
+        Err(e) => {
+            error!(error = %e, "Failed to get the block number");
+            return;
+        },
+    };
+
+    let tx_fee = splitter_coin.fee.tx_fee(rpc_client, coin.as_ref().decimals);
+    for keypair in keypairs.iter() {
+        split_pubkey(splitter_coin, keypair, current_block, tx_fee, args);
+    }
+}
+
+fn main() -> Result<(), MmError<MainError>> {
+    let args = CliArgs::parse(
+        "utxo_splitter",
+        "Splits larger P2PK unspents of the notary keys into notarization-sized outputs",
+        DEFAULT_CONF_PATH,
+        false,
+    )?;
+    let _log_guard = args.init_logging()?;
+
+    let mut conf: SplitterConfig = read_config(&args.conf_path)?;
+
+    retain_coins(&mut conf.coins, &args.selected_coins(), |coin| coin.ticker.as_str())?;
+
+    let wif_prefixes: Vec<_> = conf
+        .coins
+        .iter()
+        .map(|coin| (coin.ticker.as_str(), wif_prefix(&coin.mm_conf)))
+        .collect();
+    let keypairs = conf.keys.key_pairs(args.passphrase_stdin, &wif_prefixes)?;
+
+    let ctx = MmCtxBuilder::default().into_mm_arc();
+
+    let coins: Result<Vec<SplitterCoin>, String> = conf
+        .coins
+        .iter()
+        .map(|coin| {
+            if coin.split_value == 0 || coin.split_count == 0 || coin.target_count == 0 {
+                return Err(format!(
+                    "{} split_value, split_count and target_count must be greater than 0",
+                    coin.ticker
+                ));
+            }
+            let split_amount = coin
+                .split_value
+                .checked_mul(coin.split_count as u64)
+                .filter(|amount| amount.checked_add(coin.split_value).is_some())
+                .ok_or_else(|| format!("{} split_value * split_count is too large", coin.ticker))?;
+            let fee_policy = coin
+                .fee_policy
+                .clone()
+                .unwrap_or_else(|| FeePolicy::from_mm_conf(&coin.mm_conf));
+            Ok(SplitterCoin {
+                coin: activate_coin(&ctx, &coin.ticker, &coin.mm_conf, &coin.activation_command)?,
+                split_value: coin.split_value,
+                target_count: coin.target_count,
+                split_count: coin.split_count,
+                split_amount,
+                maturity: Maturity::new(coin.maturity.clone()),
+                fee: FeeSettings::new(fee_policy, coin.max_fee),
+            })
+        })
+        .collect();
+    let coins = coins?;
+
+    loop {
+        for splitter_coin in coins.iter() {
+            split_coin(splitter_coin, &keypairs, &args);
+        }
+
+        if args.once {
+            return Ok(());
+        }
+
+        info!(secs = args.interval.as_secs(), "Sleeping until the next cycle");
+        std::thread::sleep(args.interval);
+    }
+}